@@ -0,0 +1,71 @@
+//! Procedural macros for the `afl` crate, which re-exports them
+
+use proc_macro::TokenStream;
+use quote::{format_ident, quote};
+use syn::{ItemFn, ReturnType, parse_macro_input};
+
+/// Register a function that resets static state, to be called after each iteration of the fuzz
+/// loop (see `afl::reset`).
+///
+/// The function must take no arguments and return nothing. It is registered by a static
+/// constructor, so the crate that defines it only needs to be linked into the fuzz target.
+#[proc_macro_attribute]
+pub fn on_reset(args: TokenStream, item: TokenStream) -> TokenStream {
+    if !args.is_empty() {
+        return syn::Error::new(
+            proc_macro2::TokenStream::from(args)
+                .into_iter()
+                .next()
+                .map_or_else(proc_macro2::Span::call_site, |token| token.span()),
+            "`on_reset` takes no arguments",
+        )
+        .to_compile_error()
+        .into();
+    }
+    let function = parse_macro_input!(item as ItemFn);
+    let sig = &function.sig;
+    if !sig.inputs.is_empty()
+        || !sig.generics.params.is_empty()
+        || sig.asyncness.is_some()
+        || sig.unsafety.is_some()
+        || !matches!(sig.output, ReturnType::Default)
+    {
+        return syn::Error::new_spanned(
+            sig,
+            "`on_reset` requires a safe, non-generic function that takes no arguments and returns \
+             nothing",
+        )
+        .to_compile_error()
+        .into();
+    }
+    let name = &sig.ident;
+    let constructor = format_ident!("__AFL_ON_RESET_{}", name.to_string().to_uppercase());
+    quote! {
+        #function
+
+        // Run `register` before `main`, the way C++ static constructors are run.
+        #[doc(hidden)]
+        #[used]
+        #[cfg_attr(
+            any(
+                target_os = "android",
+                target_os = "dragonfly",
+                target_os = "freebsd",
+                target_os = "illumos",
+                target_os = "linux",
+                target_os = "netbsd",
+                target_os = "openbsd",
+            ),
+            unsafe(link_section = ".init_array")
+        )]
+        #[cfg_attr(target_vendor = "apple", unsafe(link_section = "__DATA,__mod_init_func"))]
+        #[cfg_attr(windows, unsafe(link_section = ".CRT$XCU"))]
+        static #constructor: extern "C" fn() = {
+            extern "C" fn register() {
+                ::afl::reset::register(#name);
+            }
+            register
+        };
+    }
+    .into()
+}
@@ -0,0 +1,42 @@
+use std::env;
+use std::path::PathBuf;
+
+/// Overrides the AFL++ source directory, which by default is the `AFLplusplus` submodule
+const AFLPLUSPLUS_DIR: &str = "AFL_SYS_AFLPLUSPLUS_DIR";
+
+fn main() {
+    println!("cargo:rerun-if-env-changed={AFLPLUSPLUS_DIR}");
+
+    // The `afl` crate supports only Unix.
+    if env::var_os("CARGO_CFG_UNIX").is_none() {
+        return;
+    }
+
+    let aflplusplus_dir = env::var_os(AFLPLUSPLUS_DIR).map_or_else(
+        || PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("AFLplusplus"),
+        PathBuf::from,
+    );
+    let source = aflplusplus_dir
+        .join("instrumentation")
+        .join("afl-compiler-rt.o.c");
+    assert!(
+        source.exists(),
+        "Could not find `{}`. Try running `git submodule update --init`, or set `{AFLPLUSPLUS_DIR}` \
+         to the path of an AFL++ checkout.",
+        source.display()
+    );
+    println!("cargo:rerun-if-changed={}", aflplusplus_dir.display());
+
+    // These are the flags that AFL++'s `GNUmakefile.llvm` builds `afl-compiler-rt.o` with. The
+    // runtime is built with `-O3` even in debug builds, as every edge of the fuzz target calls into
+    // it.
+    cc::Build::new()
+        .file(source)
+        .include(aflplusplus_dir.join("include"))
+        .include(aflplusplus_dir.join("instrumentation"))
+        .opt_level(3)
+        .pic(true)
+        .warnings(false)
+        .flag("-w")
+        .compile("afl-compiler-rt");
+}
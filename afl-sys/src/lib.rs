@@ -0,0 +1,20 @@
+//! Bindings to AFL++'s instrumentation runtime (`afl-compiler-rt.o`), which this crate's build
+//! script compiles and links
+//!
+//! This crate is an implementation detail of the `afl` crate. Use that crate instead.
+
+#![no_std]
+
+unsafe extern "C" {
+    pub fn __afl_persistent_loop(counter: usize) -> isize;
+    pub fn __afl_manual_init();
+
+    pub static __afl_fuzz_len: *const u32;
+    pub static __afl_fuzz_ptr: *const u8;
+
+    pub static mut __afl_area_ptr: *mut u8;
+    pub static __afl_map_size: u32;
+
+    pub static mut __afl_dictionary: *mut u8;
+    pub static mut __afl_dictionary_len: u32;
+}
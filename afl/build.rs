@@ -14,4 +14,13 @@ fn main() {
         println!("cargo:warning=You appear to be building `afl` not under `cargo-afl`.");
         println!("cargo:warning=Perhaps you used `cargo build` instead of `cargo afl build`?");
     }
+
+    // `cargo afl build` sets `AFL_RS_TARGET_CPU` to the CPU passed to `-C target-cpu`. Together with
+    // the target features that CPU implies, it is recorded in the fuzz target (see
+    // `TARGET_CPU_MARKER` in `src/inputs.rs`).
+    println!("cargo:rerun-if-env-changed=AFL_RS_TARGET_CPU");
+    let target_cpu = env::var("AFL_RS_TARGET_CPU").unwrap_or_default();
+    let target_features = env::var("CARGO_CFG_TARGET_FEATURE").unwrap_or_default();
+    println!("cargo:rustc-env=AFL_RS_TARGET_CPU={target_cpu}");
+    println!("cargo:rustc-env=AFL_RS_TARGET_FEATURES={target_features}");
 }
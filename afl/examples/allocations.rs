@@ -0,0 +1,49 @@
+#![allow(clippy::manual_assert)]
+
+// A fuzz target that allocates as its input says, for testing `afl::alloc::LimitingAllocator`:
+//
+//   - `big` allocates 2 MB, which exceeds `AFL_RS_MALLOC_LIMIT_MB=1`.
+//   - `realloc` grows and shrinks a buffer, and checks that `afl::alloc::usage` follows.
+//   - `leak` leaks a `Box`, and frees more memory than that, which was allocated before the first
+//     iteration. With `AFL_RS_DETECT_LEAKS=1`, the leak is still reported.
+//
+//   `cargo run -p cargo-afl -- afl build --example allocations --manifest-path afl/Cargo.toml`
+//   `printf big > /tmp/big`
+//   `AFL_RS_MALLOC_LIMIT_MB=1 target/debug/examples/allocations /tmp/big`
+
+use afl::alloc::{LimitingAllocator, usage};
+use std::hint::black_box;
+use std::sync::Mutex;
+
+#[global_allocator]
+static ALLOC: LimitingAllocator = LimitingAllocator::new();
+
+static PREEXISTING: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+fn main() {
+    PREEXISTING.lock().unwrap().push(vec![1; 4096]);
+    afl::fuzz!(|data: &[u8]| {
+        match data {
+            b"big" => {
+                black_box(vec![1u8; 2 << 20]);
+            }
+            b"realloc" => {
+                let live = || usage().live;
+                let capacity = |buffer: &Vec<u8>| isize::try_from(buffer.capacity()).unwrap();
+                let before = live();
+                let mut buffer = Vec::<u8>::with_capacity(16);
+                buffer.reserve_exact(1 << 20);
+                assert_eq!(live() - before, capacity(&buffer));
+                buffer.shrink_to(64);
+                assert_eq!(live() - before, capacity(&buffer));
+                drop(black_box(buffer));
+                assert_eq!(live(), before);
+            }
+            b"leak" => {
+                Box::leak(black_box(Box::new([1u8; 64])));
+                PREEXISTING.lock().unwrap().clear();
+            }
+            _ => {}
+        }
+    });
+}
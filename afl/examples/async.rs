@@ -0,0 +1,48 @@
+#![allow(clippy::manual_assert)]
+
+// An async fuzz target whose future is woken from another thread, so that the executor has to wait
+// for it. It runs on the built-in executor, or on tokio's with `--features tokio`.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+use std::thread;
+
+/// Completes once a thread it spawns has woken it
+#[derive(Default)]
+struct Handoff(Option<Arc<AtomicBool>>);
+
+impl Future for Handoff {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<()> {
+        if let Some(done) = &self.0 {
+            return if done.load(Ordering::Acquire) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            };
+        }
+        let done = Arc::new(AtomicBool::new(false));
+        let waker = context.waker().clone();
+        thread::spawn({
+            let done = done.clone();
+            move || {
+                done.store(true, Ordering::Release);
+                waker.wake();
+            }
+        });
+        self.0 = Some(done);
+        Poll::Pending
+    }
+}
+
+fn main() {
+    afl::fuzz!(async |data: &[u8]| {
+        Handoff::default().await;
+        if data.first() == Some(&b'a') {
+            panic!("Crash!");
+        }
+    });
+}
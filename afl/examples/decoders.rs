@@ -0,0 +1,60 @@
+#![allow(clippy::manual_assert)]
+
+// A fuzz target for each way `afl::FuzzInput` decodes inputs, selected by `DECODER`. Each one
+// crashes on one decoded value, so that a test can check which inputs decode to it.
+//
+//   `cargo run -p cargo-afl -- afl build --example decoders --features bincode,json,postcard \
+//     --manifest-path afl/Cargo.toml`
+//   `printf '{"x":1,"y":2}' > /tmp/point.json`
+//   `DECODER=json target/debug/examples/decoders /tmp/point.json`
+
+use afl::{Bincode, Json, Postcard, TakeRest};
+
+#[derive(arbitrary::Arbitrary, serde::Deserialize, Debug, PartialEq)]
+struct Point {
+    x: u8,
+    y: u8,
+}
+
+const CRASH: Point = Point { x: 1, y: 2 };
+
+fn main() {
+    match std::env::var("DECODER").as_deref() {
+        Ok("str") => afl::fuzz!(|s: &str| {
+            if s == "crash" {
+                panic!("Crash!");
+            }
+        }),
+        Ok("string") => afl::fuzz!(|s: String| {
+            if s == "crash" {
+                panic!("Crash!");
+            }
+        }),
+        Ok("take_rest") => afl::fuzz!(|input: TakeRest<(u8, &[u8])>| {
+            if input.0 == (1, b"crash") {
+                panic!("Crash!");
+            }
+        }),
+        Ok("arbitrary") => afl::fuzz!(|point: Point| {
+            if point == CRASH {
+                panic!("Crash!");
+            }
+        }),
+        Ok("json") => afl::fuzz!(|point: Json<Point>| {
+            if point.0 == CRASH {
+                panic!("Crash!");
+            }
+        }),
+        Ok("postcard") => afl::fuzz!(|point: Postcard<Point>| {
+            if point.0 == CRASH {
+                panic!("Crash!");
+            }
+        }),
+        Ok("bincode") => afl::fuzz!(|point: Bincode<Point>| {
+            if point.0 == CRASH {
+                panic!("Crash!");
+            }
+        }),
+        _ => panic!("set `DECODER` to one of the decoders"),
+    }
+}
@@ -0,0 +1,19 @@
+// Demonstrates `afl::dictionary!`: afl-fuzz loads the tokens when the forkserver starts ("Loaded 3
+// autodictionary entries") and splices them into inputs, which finds the crash much sooner than
+// mutating bytes does.
+
+#![allow(clippy::manual_assert)]
+
+afl::dictionary!["LOGIN", "LOGOUT", b"\x7fAFL"];
+
+fn main() {
+    afl::fuzz!(|data: &[u8]| {
+        let Ok(data) = std::str::from_utf8(data) else {
+            return;
+        };
+        let mut words = data.split_whitespace();
+        if words.next() == Some("LOGIN") && words.next() == Some("LOGOUT") {
+            panic!("Logged out!");
+        }
+    });
+}
@@ -0,0 +1,23 @@
+// Hangs on inputs that start with `a`, which the watchdog turns into crashes with a backtrace
+//
+//   `cargo run -p cargo-afl -- afl build --example hang --manifest-path afl/Cargo.toml`
+//   `printf a > /tmp/a`
+//   `AFL_RS_TIMEOUT=100 target/debug/examples/hang /tmp/a`
+
+fn main() {
+    afl::Fuzzer::new()
+        .watchdog(true)
+        .run(|data: &[u8]| {
+            if data.first() == Some(&b'a') {
+                spin();
+            }
+        })
+        .unwrap();
+}
+
+#[inline(never)]
+fn spin() -> ! {
+    loop {
+        std::hint::spin_loop();
+    }
+}
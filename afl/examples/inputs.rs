@@ -0,0 +1,23 @@
+#![allow(clippy::manual_assert)]
+
+// A custom loop: prints the length of each input, rejects the seed input (`cargo-afl/input/zero`),
+// and crashes on inputs that start with `a`
+//
+//   `cargo run -p cargo-afl -- afl build --example inputs --manifest-path afl/Cargo.toml`
+//   `printf abc > /tmp/abc`
+//   `target/debug/examples/inputs cargo-afl/input/zero /tmp/abc`
+
+fn main() {
+    std::panic::set_hook(Box::new(|_| std::process::abort()));
+    let mut inputs = afl::inputs();
+    while let Some(data) = inputs.next() {
+        println!("{}", data.len());
+        if data.first() == Some(&0) {
+            inputs.reject();
+            continue;
+        }
+        if data.first() == Some(&b'a') {
+            panic!("Crash!");
+        }
+    }
+}
@@ -0,0 +1,15 @@
+#![allow(clippy::manual_assert)]
+
+// The seed input (`cargo-afl/input/zero`) is rejected, so afl-fuzz's dry run must still see
+// coverage.
+fn main() {
+    afl::fuzz!(|data: &[u8]| {
+        if data.first() == Some(&0) {
+            return afl::Corpus::Reject;
+        }
+        if data.first() == Some(&b'a') {
+            panic!("Crash!");
+        }
+        afl::Corpus::Keep
+    });
+}
@@ -0,0 +1,27 @@
+// Like `reset_demo` with `USE_RESET=hook`, but the hook is registered by the `afl::on_reset`
+// attribute, before `main` runs
+//
+//   `cargo run -p cargo-afl -- afl build --example reset_attribute --manifest-path afl/Cargo.toml`
+//   `mkdir -p /tmp/afl-input && echo "test" > /tmp/afl-input/seed`
+//   `AFL_NO_UI=1 cargo run -p cargo-afl -- afl fuzz \
+//     -i /tmp/afl-input -o /tmp/afl-out-attribute -V 15 target/debug/examples/reset_attribute`
+
+use std::sync::Mutex;
+
+static CACHE: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+
+fn main() {
+    afl::fuzz!(|data: &[u8]| {
+        let mut cache = CACHE.lock().unwrap();
+        if cache.is_none() {
+            *cache = Some(data.to_vec());
+        }
+        drop(cache);
+        assert!(!(data.len() > 2 && data[0] == b'x'), "crash");
+    });
+}
+
+#[afl::on_reset]
+fn clear_cache() {
+    *CACHE.lock().unwrap() = None;
+}
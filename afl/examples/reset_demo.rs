@@ -13,6 +13,12 @@
 //   `USE_RESET=1 AFL_NO_UI=1 cargo run -p cargo-afl -- afl fuzz \
 //     -i /tmp/afl-input -o /tmp/afl-out-reset -V 15 target/debug/examples/reset_demo`
 //
+// With a hook registered by `afl::reset::register` (high stability):
+//   `USE_RESET=hook AFL_NO_UI=1 cargo run -p cargo-afl -- afl fuzz \
+//     -i /tmp/afl-input -o /tmp/afl-out-hook -V 15 target/debug/examples/reset_demo`
+//
+// With a hook registered by the `afl::on_reset` attribute, see the `reset_attribute` example.
+//
 // Compare stability:
 //   `grep stability /tmp/afl-out-bad/default/fuzzer_stats /tmp/afl-out-reset/default/fuzzer_stats`
 
@@ -21,10 +27,14 @@ use std::sync::Mutex;
 static CACHE: Mutex<Option<Vec<u8>>> = Mutex::new(None);
 
 fn main() {
-    if std::env::var("USE_RESET").is_ok() {
-        afl::fuzz_with_reset!(|data: &[u8]| { fuzz_body(data) }, || {
-            *CACHE.lock().unwrap() = None;
+    let use_reset = std::env::var("USE_RESET");
+    if use_reset.as_deref() == Ok("hook") {
+        afl::reset::register(clear_cache);
+        afl::fuzz!(|data: &[u8]| {
+            fuzz_body(data);
         });
+    } else if use_reset.is_ok() {
+        afl::fuzz_with_reset!(|data: &[u8]| { fuzz_body(data) }, clear_cache);
     } else {
         afl::fuzz!(|data: &[u8]| {
             fuzz_body(data);
@@ -32,6 +42,10 @@ fn main() {
     }
 }
 
+fn clear_cache() {
+    *CACHE.lock().unwrap() = None;
+}
+
 fn fuzz_body(data: &[u8]) {
     let mut cache = CACHE.lock().unwrap();
     if cache.is_none() {
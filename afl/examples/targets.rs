@@ -0,0 +1,16 @@
+#![allow(clippy::manual_assert)]
+
+fn main() {
+    afl::targets! {
+        hello => |data: &[u8]| {
+            if data.first() == Some(&b'a') {
+                panic!("Crash!");
+            }
+        },
+        goodbye => |data: &[u8]| {
+            if data.first() == Some(&b'z') {
+                panic!("Crash!");
+            }
+        },
+    }
+}
@@ -0,0 +1,319 @@
+//! A global allocator that limits how much memory each iteration may allocate
+//!
+//! A fuzz target that allocates a buffer whose length comes from the input can ask for gigabytes.
+//! Without a limit, afl-fuzz's `-m` kills it without saying where, or the allocator aborts. With
+//! [`LimitingAllocator`] installed, an iteration that allocates too much aborts with a message and
+//! a backtrace instead, like libFuzzer's `-malloc_limit_mb`:
+//!
+//! ```rust,no_run
+//! # extern crate afl;
+//! use afl::alloc::LimitingAllocator;
+//!
+//! #[global_allocator]
+//! static ALLOC: LimitingAllocator = LimitingAllocator::new().limit_mb(512);
+//! # fn main() {}
+//! ```
+//!
+//! Only allocations made while inputs are being run count, and the counts start over with each
+//! input (i.e., at each `__afl_persistent_loop` boundary).
+//!
+//! The counts also let [`Fuzzer::detect_leaks`](crate::Fuzzer::detect_leaks) find inputs whose
+//! iterations do not free what they allocate.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::backtrace::Backtrace;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+/// The default limit, which is also libFuzzer's
+const DEFAULT_LIMIT_MB: usize = 2048;
+
+/// Bytes allocated and not freed since the current iteration started; negative if the iteration
+/// freed memory that was allocated before it
+static LIVE: AtomicIsize = AtomicIsize::new(0);
+/// The largest value [`LIVE`] reached during the current iteration
+static PEAK: AtomicIsize = AtomicIsize::new(0);
+/// Whether an iteration is running, i.e., whether allocations count
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+/// Set once the limit is exceeded or a leak is found, so that reporting it does not count
+static REPORTING: AtomicBool = AtomicBool::new(false);
+/// The limit in bytes set by `AFL_RS_MALLOC_LIMIT_MB`, or 0
+static LIMIT_OVERRIDE: AtomicUsize = AtomicUsize::new(0);
+/// Whether [`LimitingAllocator`] is the global allocator
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether to record where each allocation was made, for reporting leaks
+static TRACKING: AtomicBool = AtomicBool::new(false);
+/// The size and backtrace of each allocation made since the leak check started and not freed,
+/// keyed by address
+static ALLOCATIONS: Mutex<BTreeMap<usize, (usize, Backtrace)>> = Mutex::new(BTreeMap::new());
+
+thread_local! {
+    /// Set while the allocator itself allocates (e.g., to record a backtrace), so that it does not
+    /// count or track those allocations
+    static IN_ALLOCATOR: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Wraps another allocator (by default, [`System`]), and aborts when an iteration has more than
+/// [`limit_mb`](LimitingAllocator::limit_mb) megabytes allocated. Overridden by
+/// `AFL_RS_MALLOC_LIMIT_MB`.
+pub struct LimitingAllocator<A = System> {
+    inner: A,
+    limit: usize,
+}
+
+impl LimitingAllocator {
+    /// Wrap [`System`], with a limit of 2048 megabytes
+    #[must_use]
+    pub const fn new() -> Self {
+        Self::with_allocator(System)
+    }
+}
+
+impl Default for LimitingAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A> LimitingAllocator<A> {
+    /// Wrap `inner`, with a limit of 2048 megabytes
+    pub const fn with_allocator(inner: A) -> Self {
+        Self {
+            inner,
+            limit: DEFAULT_LIMIT_MB << 20,
+        }
+    }
+
+    /// Set the limit, in megabytes
+    #[must_use]
+    pub const fn limit_mb(mut self, limit_mb: usize) -> Self {
+        self.limit = limit_mb << 20;
+        self
+    }
+
+    /// Count an allocation of `size` bytes, and return whether it should be tracked
+    fn count(&self, size: usize) -> bool {
+        if !INSTALLED.load(Ordering::Relaxed) {
+            INSTALLED.store(true, Ordering::Relaxed);
+        }
+        if !ACTIVE.load(Ordering::Relaxed)
+            || REPORTING.load(Ordering::Relaxed)
+            || IN_ALLOCATOR.get()
+        {
+            return false;
+        }
+        let size = isize::try_from(size).unwrap_or(isize::MAX);
+        let live = LIVE.fetch_add(size, Ordering::Relaxed).saturating_add(size);
+        PEAK.fetch_max(live, Ordering::Relaxed);
+        let limit = match LIMIT_OVERRIDE.load(Ordering::Relaxed) {
+            0 => self.limit,
+            limit => limit,
+        };
+        if let Ok(live) = usize::try_from(live)
+            && live > limit
+        {
+            report(live, limit);
+        }
+        true
+    }
+
+    /// Count a deallocation of `size` bytes, and return whether it should be untracked
+    fn uncount(size: usize) -> bool {
+        if !ACTIVE.load(Ordering::Relaxed)
+            || REPORTING.load(Ordering::Relaxed)
+            || IN_ALLOCATOR.get()
+        {
+            return false;
+        }
+        LIVE.fetch_sub(
+            isize::try_from(size).unwrap_or(isize::MAX),
+            Ordering::Relaxed,
+        );
+        true
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for LimitingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let counted = self.count(layout.size());
+        let ptr = unsafe { self.inner.alloc(layout) };
+        if counted {
+            track(ptr, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let counted = self.count(layout.size());
+        let ptr = unsafe { self.inner.alloc_zeroed(layout) };
+        if counted {
+            track(ptr, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if Self::uncount(layout.size()) {
+            untrack(ptr);
+        }
+        unsafe { self.inner.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let counted = if new_size > layout.size() {
+            self.count(new_size - layout.size())
+        } else {
+            Self::uncount(layout.size() - new_size)
+        };
+        let new_ptr = unsafe { self.inner.realloc(ptr, layout, new_size) };
+        if counted && !new_ptr.is_null() {
+            retrack(
+                ptr,
+                new_ptr,
+                new_size.cast_signed() - layout.size().cast_signed(),
+            );
+        }
+        new_ptr
+    }
+}
+
+/// How much memory the current iteration has allocated, as counted by [`LimitingAllocator`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// Bytes allocated and not freed since the iteration started; negative if the iteration freed
+    /// memory that was allocated before it
+    pub live: isize,
+    /// The largest value `live` reached
+    pub peak: usize,
+}
+
+/// Return how much memory the current iteration has allocated. Always zero unless
+/// [`LimitingAllocator`] is the global allocator.
+#[must_use]
+pub fn usage() -> Usage {
+    Usage {
+        live: LIVE.load(Ordering::Relaxed),
+        peak: PEAK.load(Ordering::Relaxed).unsigned_abs(),
+    }
+}
+
+/// Start counting allocations for a new iteration
+pub(crate) fn start_iteration() {
+    LIVE.store(0, Ordering::Relaxed);
+    PEAK.store(0, Ordering::Relaxed);
+    ACTIVE.store(true, Ordering::Relaxed);
+}
+
+/// Override the limit of [`LimitingAllocator`], in megabytes
+pub(crate) fn set_limit_mb(limit_mb: usize) {
+    LIMIT_OVERRIDE.store(limit_mb << 20, Ordering::Relaxed);
+}
+
+/// Whether [`LimitingAllocator`] is the global allocator
+pub(crate) fn is_installed() -> bool {
+    INSTALLED.load(Ordering::Relaxed)
+}
+
+/// Record where each allocation is made, so that leaks can be reported with backtraces
+pub(crate) fn enable_tracking() {
+    TRACKING.store(true, Ordering::Relaxed);
+}
+
+/// Start looking for memory that is allocated from now on and not freed
+pub(crate) fn start_leak_check() {
+    without_counting(|| allocations().clear());
+}
+
+/// Abort if more than `threshold` bytes were allocated since [`start_leak_check`] and not freed.
+/// Only the allocations made since then count, so freeing older memory does not offset a leak.
+pub(crate) fn check_leaks(threshold: usize) {
+    let leaked = without_counting(|| allocations().values().map(|(size, _)| size).sum());
+    if leaked > threshold {
+        report_leak(leaked);
+    }
+}
+
+fn track(ptr: *mut u8, size: usize) {
+    if !TRACKING.load(Ordering::Relaxed) || ptr.is_null() {
+        return;
+    }
+    without_counting(|| {
+        allocations().insert(ptr as usize, (size, Backtrace::force_capture()));
+    });
+}
+
+fn untrack(ptr: *mut u8) {
+    if !TRACKING.load(Ordering::Relaxed) {
+        return;
+    }
+    without_counting(|| {
+        allocations().remove(&(ptr as usize));
+    });
+}
+
+/// Move the record of `ptr` to `new_ptr`, whose size changed by `delta`. An allocation made before
+/// the leak check started is not tracked, so only the bytes it grew by since then are.
+fn retrack(ptr: *mut u8, new_ptr: *mut u8, delta: isize) {
+    if !TRACKING.load(Ordering::Relaxed) {
+        return;
+    }
+    without_counting(|| {
+        let tracked = allocations().remove(&(ptr as usize));
+        let (size, backtrace) = tracked.unwrap_or_else(|| (0, Backtrace::force_capture()));
+        let size = size.saturating_add_signed(delta);
+        if size > 0 {
+            allocations().insert(new_ptr as usize, (size, backtrace));
+        }
+    });
+}
+
+fn allocations() -> std::sync::MutexGuard<'static, BTreeMap<usize, (usize, Backtrace)>> {
+    ALLOCATIONS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn without_counting<T>(f: impl FnOnce() -> T) -> T {
+    IN_ALLOCATOR.set(true);
+    let result = f();
+    IN_ALLOCATOR.set(false);
+    result
+}
+
+/// How many of the largest leaked allocations to show
+const LEAKS_SHOWN: usize = 10;
+
+#[cold]
+fn report_leak(leaked: usize) -> ! {
+    REPORTING.store(true, Ordering::Relaxed);
+    let allocations = allocations();
+    let mut leaks = allocations.values().collect::<Vec<_>>();
+    leaks.sort_by_key(|(size, _)| std::cmp::Reverse(*size));
+    let mut message = format!(
+        "afl: leak detected: the input retained {leaked} bytes in {} allocation{}\n",
+        leaks.len(),
+        if leaks.len() == 1 { "" } else { "s" }
+    );
+    for (size, backtrace) in leaks.iter().take(LEAKS_SHOWN) {
+        let _ = writeln!(message, "{size} bytes allocated at\n{backtrace}");
+    }
+    if leaks.len() > LEAKS_SHOWN {
+        let _ = writeln!(message, "... and {} more", leaks.len() - LEAKS_SHOWN);
+    }
+    let _ = std::io::stderr().write_all(message.as_bytes());
+    std::process::abort();
+}
+
+#[cold]
+fn report(live: usize, limit: usize) -> ! {
+    REPORTING.store(true, Ordering::Relaxed);
+    let message = format!(
+        "afl: malloc limit exceeded: {live} bytes (the limit is {limit} bytes) at\n{}\n",
+        Backtrace::force_capture()
+    );
+    let _ = std::io::stderr().write_all(message.as_bytes());
+    std::process::abort();
+}
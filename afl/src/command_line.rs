@@ -0,0 +1,156 @@
+//! Command lines decoded from inputs, for fuzzing programs through their arguments; see
+//! [`fuzz_args!`](crate::fuzz_args)
+
+use crate::FuzzInput;
+use std::ffi::OsString;
+
+/// The separator between an input's sections: arguments, environment variables, and standard input
+const SECTION_SEPARATOR: &[u8] = b"\0\0";
+
+/// The arguments, environment variables, and standard input of one run of a command-line program
+///
+/// An input is decoded as its arguments, separated by NUL bytes, then, after two NUL bytes, its
+/// environment variables (`KEY=VALUE`, separated by NUL bytes), then, after two more NUL bytes,
+/// its standard input. Each section may be empty or missing. E.g., `build\0--release\0\0RUST_LOG=
+/// debug\0\0[package]` is `["build", "--release"]`, with `RUST_LOG` set to `debug`, and
+/// `[package]` on standard input. A trailing NUL byte adds an empty argument, and variables without
+/// `=` or with an empty name are skipped.
+///
+/// [`args`](CommandLine::args) starts with the program name, as [`std::env::args_os`] does, which
+/// is the fuzz target's own. So an argument parser's `parse_from` can be passed `args` as is.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommandLine {
+    pub args: Vec<OsString>,
+    pub env: Vec<(OsString, OsString)>,
+    pub stdin: Vec<u8>,
+}
+
+impl FuzzInput<'_> for CommandLine {
+    fn decode(data: &[u8]) -> Option<Self> {
+        let (args, rest) = split_once(data, SECTION_SEPARATOR);
+        let (env, stdin) = split_once(rest, SECTION_SEPARATOR);
+
+        let program = std::env::args_os()
+            .next()
+            .unwrap_or_else(|| OsString::from("fuzz"));
+        let args = std::iter::once(program)
+            .chain(items(args).map(os_string))
+            .collect();
+        let env = items(env)
+            .filter_map(|variable| {
+                let index = variable.iter().position(|&b| b == b'=')?;
+                (index != 0).then(|| {
+                    (
+                        os_string(&variable[..index]),
+                        os_string(&variable[index + 1..]),
+                    )
+                })
+            })
+            .collect();
+        Some(Self {
+            args,
+            env,
+            stdin: stdin.to_vec(),
+        })
+    }
+}
+
+/// `data` up to the first `separator`, and what follows it, which is empty if there is none
+fn split_once<'a>(data: &'a [u8], separator: &[u8]) -> (&'a [u8], &'a [u8]) {
+    data.windows(separator.len())
+        .position(|window| window == separator)
+        .map_or((data, &[]), |index| {
+            (&data[..index], &data[index + separator.len()..])
+        })
+}
+
+/// The NUL-separated items of a section. An empty section has none, rather than one empty item.
+fn items(section: &[u8]) -> impl Iterator<Item = &[u8]> {
+    (!section.is_empty())
+        .then(|| section.split(|&b| b == 0))
+        .into_iter()
+        .flatten()
+}
+
+#[cfg(unix)]
+fn os_string(bytes: &[u8]) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(bytes.to_vec())
+}
+
+#[cfg(not(unix))]
+fn os_string(bytes: &[u8]) -> OsString {
+    OsString::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[track_caller]
+    fn assert_decodes(data: &[u8], args: &[&str], env: &[(&str, &str)], stdin: &[u8]) {
+        let command_line = CommandLine::decode(data).unwrap();
+        // The program name is the test binary's.
+        assert_eq!(
+            command_line.args[1..]
+                .iter()
+                .map(|arg| arg.to_str().unwrap())
+                .collect::<Vec<_>>(),
+            args
+        );
+        assert_eq!(
+            command_line
+                .env
+                .iter()
+                .map(|(key, value)| (key.to_str().unwrap(), value.to_str().unwrap()))
+                .collect::<Vec<_>>(),
+            env
+        );
+        assert_eq!(command_line.stdin, stdin);
+    }
+
+    #[test]
+    fn all_sections() {
+        assert_decodes(
+            b"build\0--release\0\0RUST_LOG=debug\0\0[package]",
+            &["build", "--release"],
+            &[("RUST_LOG", "debug")],
+            b"[package]",
+        );
+    }
+
+    #[test]
+    fn empty_sections() {
+        assert_decodes(b"", &[], &[], b"");
+        assert_decodes(b"\0\0", &[], &[], b"");
+        assert_decodes(b"\0\0\0\0", &[], &[], b"");
+        assert_decodes(b"\0\0\0\0stdin", &[], &[], b"stdin");
+        assert_decodes(b"arg\0\0\0\0", &["arg"], &[], b"");
+        assert_decodes(b"\0\0K=V", &[], &[("K", "V")], b"");
+    }
+
+    #[test]
+    fn trailing_nul_is_an_empty_argument() {
+        assert_decodes(b"arg\0", &["arg", ""], &[], b"");
+        assert_decodes(b"\0", &["", ""], &[], b"");
+    }
+
+    #[test]
+    fn invalid_variables_are_skipped() {
+        assert_decodes(
+            b"\0\0NO_EQUALS\0=value\0EMPTY=\0K=a=b",
+            &[],
+            &[("EMPTY", ""), ("K", "a=b")],
+            b"",
+        );
+    }
+
+    #[test]
+    fn three_nuls_end_a_section_at_the_first_two() {
+        // The third NUL separates two empty variables, which are skipped.
+        assert_decodes(b"arg\0\0\0", &["arg"], &[], b"");
+        assert_decodes(b"arg\0\0\0K=V", &["arg"], &[("K", "V")], b"");
+        // Standard input keeps its NUL bytes.
+        assert_decodes(b"\0\0\0\0\0\0\0", &[], &[], b"\0\0\0");
+    }
+}
@@ -0,0 +1,101 @@
+//! What a fuzz target's closure can return
+
+use std::fmt::Debug;
+
+/// Whether afl-fuzz may add an input to its queue
+///
+/// Returning `Corpus::Reject` from a fuzz target discards the coverage of that run, so afl-fuzz
+/// does not consider the input interesting. This is useful for inputs that the fuzz target
+/// recognizes as invalid early on, and is the analog of returning `-1` from a libFuzzer target.
+///
+/// ```rust,no_run
+/// # #[macro_use] extern crate afl;
+/// # fn main() {
+/// fuzz!(|data: &[u8]| {
+///     let Ok(s) = std::str::from_utf8(data) else {
+///         return afl::Corpus::Reject;
+///     };
+///     let _ = s.parse::<f64>();
+///     afl::Corpus::Keep
+/// });
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Corpus {
+    #[default]
+    Keep,
+    Reject,
+}
+
+/// A value that a fuzz target's closure can return: `()`, a [`Corpus`] verdict, or a `Result` of
+/// either
+///
+/// An `Err` is a crash if [`Fuzzer::errors_are_crashes`](crate::Fuzzer::errors_are_crashes) is
+/// set, and is otherwise treated like `Corpus::Keep`.
+pub trait FuzzOutcome {
+    /// The verdict, or the `Debug` representation of the error the fuzz target returned
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the fuzz target did.
+    fn into_result(self) -> Result<Corpus, String>;
+}
+
+impl FuzzOutcome for () {
+    fn into_result(self) -> Result<Corpus, String> {
+        Ok(Corpus::Keep)
+    }
+}
+
+impl FuzzOutcome for Corpus {
+    fn into_result(self) -> Result<Corpus, String> {
+        Ok(self)
+    }
+}
+
+impl<T: FuzzOutcome, E: Debug> FuzzOutcome for Result<T, E> {
+    fn into_result(self) -> Result<Corpus, String> {
+        match self {
+            Ok(outcome) => outcome.into_result(),
+            Err(error) => Err(format!("{error:?}")),
+        }
+    }
+}
+
+/// What the `fuzz!` macros' closures return when they convert the input to another type
+#[doc(hidden)]
+pub enum __Converted<O> {
+    /// The input could not be converted, so the fuzz target did not run. The input is rejected:
+    /// its coverage is only that of the decoder, and would fill afl-fuzz's queue with inputs
+    /// that never reach the fuzz target.
+    Failed,
+    Ran(O),
+}
+
+impl<O: FuzzOutcome> FuzzOutcome for __Converted<O> {
+    fn into_result(self) -> Result<Corpus, String> {
+        match self {
+            Self::Failed => Ok(Corpus::Reject),
+            Self::Ran(outcome) => outcome.into_result(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inputs_that_do_not_convert_are_rejected() {
+        assert_eq!(__Converted::<()>::Failed.into_result(), Ok(Corpus::Reject));
+        assert_eq!(__Converted::Ran(()).into_result(), Ok(Corpus::Keep));
+        assert_eq!(
+            __Converted::Ran(Corpus::Reject).into_result(),
+            Ok(Corpus::Reject)
+        );
+        assert_eq!(
+            __Converted::Ran(Err::<(), _>("error")).into_result(),
+            Err(String::from("\"error\""))
+        );
+    }
+}
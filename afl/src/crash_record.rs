@@ -0,0 +1,125 @@
+//! Records of panics, written next to the inputs that cause them, so that triage can start from the
+//! panic message rather than from a re-run
+//!
+//! afl-fuzz discards the target's standard error. When `AFL_RS_CRASH_RECORDS_DIR` is set (`cargo
+//! afl fuzz` sets it), a panic writes the message, location, backtrace, and any
+//! [`context!`](crate::context) of the current iteration to `<hash>.txt` in that directory, where
+//! `<hash>` is the input's 64-bit FNV-1a hash in hexadecimal. `cargo afl fuzz` then copies each
+//! record to `crash_records/<crash file>.txt`, next to the `crashes` directory.
+
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::fmt::{self, Write as _};
+use std::panic::PanicHookInfo;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock, PoisonError};
+
+const RECORDS_DIR_ENV_VAR: &str = "AFL_RS_CRASH_RECORDS_DIR";
+
+static RECORDS_DIR: OnceLock<Option<PathBuf>> = OnceLock::new();
+/// The input of the current iteration
+static INPUT_PTR: AtomicPtr<u8> = AtomicPtr::new(std::ptr::null_mut());
+static INPUT_LEN: AtomicUsize = AtomicUsize::new(0);
+/// What [`context!`](crate::context) recorded during the current iteration
+static CONTEXT: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+fn records_dir() -> Option<&'static PathBuf> {
+    RECORDS_DIR
+        .get_or_init(|| std::env::var_os(RECORDS_DIR_ENV_VAR).map(PathBuf::from))
+        .as_ref()
+}
+
+/// Whether records are written. Call this before the forkserver starts, so that the environment is
+/// read only once.
+pub(crate) fn enabled() -> bool {
+    records_dir().is_some()
+}
+
+/// Forget the context of the previous iteration, and remember `input` in case this one panics.
+pub(crate) fn start_iteration(input: &[u8]) {
+    INPUT_PTR.store(input.as_ptr().cast_mut(), Ordering::Relaxed);
+    INPUT_LEN.store(input.len(), Ordering::Relaxed);
+    CONTEXT
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clear();
+}
+
+/// Record `args` for the current iteration; see [`context!`](crate::context).
+#[doc(hidden)]
+pub fn __context(args: fmt::Arguments<'_>) {
+    if enabled() {
+        CONTEXT
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(args.to_string());
+    }
+}
+
+/// Write a record of the panic described by `info`.
+pub(crate) fn write_panic(info: &PanicHookInfo<'_>) {
+    let location = info.location().map(ToString::to_string);
+    write(
+        info.payload_as_str().unwrap_or("Box<dyn Any>"),
+        location.as_deref(),
+        Some(&Backtrace::force_capture()),
+    );
+}
+
+/// The message of a panic, from its payload
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>")
+}
+
+/// Write a record of a panic with `message`. Its `location` and `backtrace` are known only in the
+/// panic hook.
+pub(crate) fn write(message: &str, location: Option<&str>, backtrace: Option<&Backtrace>) {
+    let Some(dir) = records_dir() else {
+        return;
+    };
+    let ptr = INPUT_PTR.load(Ordering::Relaxed);
+    let input = if ptr.is_null() {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(ptr, INPUT_LEN.load(Ordering::Relaxed)) }
+    };
+    let hash = fnv1a(input);
+
+    let mut record = format!("input: {} bytes, hash {hash:016x}\n", input.len());
+    let _ = writeln!(
+        record,
+        "panicked at {}:\n{message}",
+        location.unwrap_or("an unknown location")
+    );
+    let context = CONTEXT.lock().unwrap_or_else(PoisonError::into_inner);
+    if !context.is_empty() {
+        record.push_str("\ncontext:\n");
+        for entry in context.iter() {
+            let _ = writeln!(record, "    {entry}");
+        }
+    }
+    if let Some(backtrace) = backtrace {
+        let _ = write!(record, "\nstack backtrace:\n{backtrace}");
+    }
+
+    if let Err(error) = std::fs::create_dir_all(dir)
+        .and_then(|()| std::fs::write(dir.join(format!("{hash:016x}.txt")), record))
+    {
+        eprintln!(
+            "afl: could not write a crash record to `{}`: {error}",
+            dir.display()
+        );
+    }
+}
+
+/// The 64-bit FNV-1a hash, which `cargo afl` also computes to match records with crash files
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
@@ -0,0 +1,196 @@
+//! Dictionaries declared next to the code that parses them, with [`dictionary!`](crate::dictionary)
+//!
+//! Each `dictionary!` places its tokens in the `afl_rs_dictionary` linker section, each preceded by
+//! its length, which is the format of AFL++'s autodictionary. [`init`](crate::init) hands the
+//! section to the runtime, which sends it to afl-fuzz when the forkserver starts. The linker defines
+//! the bounds of the section only for ELF targets (e.g., Linux), so elsewhere `dictionary!` does
+//! nothing.
+
+use crate::{__afl_dictionary, __afl_dictionary_len};
+use std::sync::Once;
+
+/// The longest token afl-fuzz accepts (`MAX_DICT_FILE`)
+const MAX_TOKEN_LEN: usize = 128;
+
+/// A token passed to [`dictionary!`](crate::dictionary): a string, or a byte string or slice
+#[doc(hidden)]
+pub struct __Token<T>(pub T);
+
+impl __Token<&'static str> {
+    #[must_use]
+    pub const fn bytes(self) -> &'static [u8] {
+        self.0.as_bytes()
+    }
+}
+
+impl<const N: usize> __Token<&'static [u8; N]> {
+    #[must_use]
+    pub const fn bytes(self) -> &'static [u8] {
+        self.0
+    }
+}
+
+impl __Token<&'static [u8]> {
+    #[must_use]
+    pub const fn bytes(self) -> &'static [u8] {
+        self.0
+    }
+}
+
+/// The size of `tokens` in the autodictionary format. Fails to compile if a token is empty or too
+/// long.
+#[doc(hidden)]
+#[must_use]
+pub const fn __encoded_len(tokens: &[&[u8]]) -> usize {
+    let mut len = 0;
+    let mut i = 0;
+    while i < tokens.len() {
+        assert!(
+            !tokens[i].is_empty() && tokens[i].len() <= MAX_TOKEN_LEN,
+            "dictionary tokens must be 1 to 128 bytes long"
+        );
+        len += 1 + tokens[i].len();
+        i += 1;
+    }
+    len
+}
+
+/// `tokens` in the autodictionary format: each token preceded by its length, in one byte
+#[doc(hidden)]
+#[must_use]
+#[allow(clippy::cast_possible_truncation)]
+pub const fn __encode<const N: usize>(tokens: &[&[u8]]) -> [u8; N] {
+    let mut encoded = [0; N];
+    let mut offset = 0;
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = tokens[i];
+        encoded[offset] = token.len() as u8;
+        offset += 1;
+        let mut j = 0;
+        while j < token.len() {
+            encoded[offset] = token[j];
+            offset += 1;
+            j += 1;
+        }
+        i += 1;
+    }
+    encoded
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "illumos",
+    target_os = "linux",
+    target_os = "netbsd",
+    target_os = "openbsd",
+))]
+mod section {
+    unsafe extern "C" {
+        static __start_afl_rs_dictionary: u8;
+        static __stop_afl_rs_dictionary: u8;
+    }
+
+    /// Makes the section exist in binaries that declare no dictionary, so that the linker defines
+    /// its bounds. Empty entries are skipped.
+    #[used]
+    #[unsafe(link_section = "afl_rs_dictionary")]
+    static EMPTY: [u8; 1] = [0];
+
+    pub(super) fn contents() -> &'static [u8] {
+        unsafe {
+            let start = &raw const __start_afl_rs_dictionary;
+            let stop = &raw const __stop_afl_rs_dictionary;
+            std::slice::from_raw_parts(start, stop.offset_from_unsigned(start))
+        }
+    }
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "illumos",
+    target_os = "linux",
+    target_os = "netbsd",
+    target_os = "openbsd",
+)))]
+mod section {
+    pub(super) fn contents() -> &'static [u8] {
+        &[]
+    }
+}
+
+/// Give the runtime the tokens declared with `dictionary!`, so that it sends them to afl-fuzz. Must
+/// be called before the forkserver starts.
+pub(crate) fn install() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let dictionary = entries(section::contents());
+        if dictionary.is_empty() {
+            return;
+        }
+        let Ok(len) = u32::try_from(dictionary.len()) else {
+            return;
+        };
+        unsafe {
+            __afl_dictionary = dictionary.leak().as_mut_ptr();
+            __afl_dictionary_len = len;
+        }
+    });
+}
+
+/// The entries of `section` in the autodictionary format, without the empty ones that pad it. A
+/// truncated entry at the end is dropped.
+fn entries(section: &[u8]) -> Vec<u8> {
+    let mut dictionary = Vec::new();
+    let mut rest = section;
+    while let [len, tail @ ..] = rest {
+        let len = usize::from(*len);
+        if len > tail.len() {
+            break;
+        }
+        if len != 0 {
+            dictionary.extend_from_slice(&rest[..=len]);
+        }
+        rest = &tail[len..];
+    }
+    dictionary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKENS: &[&[u8]] = &[
+        __Token("LOGIN").bytes(),
+        __Token(b"\x7fAFL").bytes(),
+        __Token(&[0u8; MAX_TOKEN_LEN]).bytes(),
+    ];
+    const ENCODED: [u8; __encoded_len(TOKENS)] = __encode(TOKENS);
+
+    #[test]
+    fn tokens_are_preceded_by_their_length() {
+        assert_eq!(ENCODED.len(), 6 + 5 + 1 + MAX_TOKEN_LEN);
+        assert_eq!(&ENCODED[..11], b"\x05LOGIN\x04\x7fAFL");
+        assert_eq!(usize::from(ENCODED[11]), MAX_TOKEN_LEN);
+        assert!(ENCODED[12..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn empty_and_truncated_entries_are_dropped() {
+        assert_eq!(entries(b"\0\x02ab\0\0\x01c\0"), b"\x02ab\x01c");
+        assert_eq!(entries(b"\x01a\x05abc"), b"\x01a");
+        assert_eq!(entries(b""), b"");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn section_contains_the_padding() {
+        // This crate declares no tokens, but `section::EMPTY` makes the section exist.
+        assert!(section::contents().contains(&0));
+        assert!(entries(section::contents()).is_empty());
+    }
+}
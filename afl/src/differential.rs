@@ -0,0 +1,169 @@
+//! Differential fuzzing, which runs two implementations on each input and crashes when they
+//! disagree; see [`differential!`](crate::differential)
+
+use crate::crash_record::panic_message;
+use std::fmt::{Debug, Write as _};
+use std::panic::{self, AssertUnwindSafe};
+
+/// One implementation's result: the value it returned, or its panic message
+enum Outcome<T> {
+    Returned(T),
+    Panicked(String),
+}
+
+impl<T: Debug> Outcome<T> {
+    fn run(f: impl FnOnce() -> T) -> Self {
+        match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(value) => Self::Returned(value),
+            Err(payload) => Self::Panicked(panic_message(&*payload).to_owned()),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Self::Returned(value) => format!("{value:#?}"),
+            Self::Panicked(message) => format!("panicked: {message}"),
+        }
+    }
+}
+
+/// Run `left` and `right`, each named by the source of its expression, and panic with a report if
+/// their results differ or either of them panics. A panic in both is a bug they share, which
+/// `fuzz!` would have found too.
+#[doc(hidden)]
+#[track_caller]
+pub fn __differential<I, L, R>(
+    input: &I,
+    (left_name, left): (&str, impl FnOnce() -> L),
+    (right_name, right): (&str, impl FnOnce() -> R),
+) where
+    I: Debug + ?Sized,
+    L: Debug + PartialEq<R>,
+    R: Debug,
+{
+    let left = Outcome::run(left);
+    let right = Outcome::run(right);
+    let summary = match (&left, &right) {
+        (Outcome::Returned(left), Outcome::Returned(right)) if left == right => return,
+        (Outcome::Panicked(_), Outcome::Panicked(_)) => "both implementations panicked",
+        _ => "the implementations disagree",
+    };
+
+    let left = left.describe();
+    let right = right.describe();
+    let mut report = format!("{summary}\n\ninput:\n{input:#?}\n");
+    let _ = write!(
+        report,
+        "\nleft (`{left_name}`):\n{left}\n\nright (`{right_name}`):\n{right}\n"
+    );
+    let _ = write!(report, "\ndiff (- left, + right):\n{}", diff(&left, &right));
+    panic!("{report}");
+}
+
+/// A line-by-line diff of `left` and `right`, from their longest common subsequence of lines
+fn diff(left: &str, right: &str) -> String {
+    let left = left.lines().collect::<Vec<_>>();
+    let right = right.lines().collect::<Vec<_>>();
+
+    // `common[i][j]` is the length of the longest common subsequence of `left[i..]` and
+    // `right[j..]`.
+    let mut common = vec![vec![0usize; right.len() + 1]; left.len() + 1];
+    for i in (0..left.len()).rev() {
+        for j in (0..right.len()).rev() {
+            common[i][j] = if left[i] == right[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < left.len() || j < right.len() {
+        if i < left.len() && j < right.len() && left[i] == right[j] {
+            let _ = writeln!(diff, "  {}", left[i]);
+            i += 1;
+            j += 1;
+        } else if j == right.len() || (i < left.len() && common[i + 1][j] >= common[i][j + 1]) {
+            let _ = writeln!(diff, "- {}", left[i]);
+            i += 1;
+        } else {
+            let _ = writeln!(diff, "+ {}", right[j]);
+            j += 1;
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_of_equal_strings_has_no_changes() {
+        assert_eq!(diff("a\nb", "a\nb"), "  a\n  b\n");
+        assert_eq!(diff("", ""), "");
+    }
+
+    #[test]
+    fn diff_shows_changed_lines() {
+        assert_eq!(diff("a\nb\nc", "a\nx\nc"), "  a\n- b\n+ x\n  c\n");
+        assert_eq!(diff("a\nb", "b\nc"), "- a\n  b\n+ c\n");
+        assert_eq!(diff("", "a"), "+ a\n");
+        assert_eq!(diff("a", ""), "- a\n");
+    }
+
+    #[test]
+    fn diff_keeps_the_longest_common_subsequence() {
+        assert_eq!(
+            diff("a\nb\nc\nd", "b\nc\nd\na"),
+            "- a\n  b\n  c\n  d\n+ a\n"
+        );
+    }
+
+    fn outcome(left: impl FnOnce() -> u8, right: impl FnOnce() -> u8) -> Option<String> {
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(|_| {}));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            __differential(&0, ("left", left), ("right", right));
+        }));
+        panic::set_hook(hook);
+        result
+            .err()
+            .map(|payload| panic_message(&*payload).to_owned())
+    }
+
+    #[test]
+    fn equal_results_agree() {
+        assert_eq!(outcome(|| 1, || 1), None);
+    }
+
+    #[test]
+    fn different_results_disagree() {
+        let report = outcome(|| 1, || 2).unwrap();
+        assert!(
+            report.starts_with("the implementations disagree"),
+            "{report}"
+        );
+        assert!(report.contains("- 1\n+ 2\n"), "{report}");
+    }
+
+    #[test]
+    fn a_panic_on_either_side_is_reported() {
+        let report = outcome(|| panic!("left failed"), || 1).unwrap();
+        assert!(
+            report.starts_with("the implementations disagree"),
+            "{report}"
+        );
+        assert!(report.contains("panicked: left failed"), "{report}");
+
+        let report = outcome(|| panic!("left failed"), || panic!("right failed")).unwrap();
+        assert!(
+            report.starts_with("both implementations panicked"),
+            "{report}"
+        );
+        assert!(report.contains("panicked: left failed"), "{report}");
+        assert!(report.contains("panicked: right failed"), "{report}");
+    }
+}
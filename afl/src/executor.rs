@@ -0,0 +1,95 @@
+//! The executor that async fuzz targets run on (see [`Fuzzer::run_async`](crate::Fuzzer::run_async))
+//!
+//! It is created once, before the forkserver starts, and runs every iteration's future to
+//! completion on the fuzzing thread. A future that is never woken keeps its iteration waiting until
+//! afl-fuzz's timeout, so afl-fuzz records the input as a hang (or the watchdog aborts with a
+//! backtrace).
+//!
+//! With the `tokio` feature, the executor is a current-thread tokio runtime with all of its drivers
+//! enabled, so that fuzz targets can use tokio's I/O, timers, and `tokio::spawn`.
+
+#[cfg(not(feature = "tokio"))]
+pub(crate) use local::Executor;
+
+#[cfg(feature = "tokio")]
+pub(crate) use tokio_runtime::Executor;
+
+#[cfg(not(feature = "tokio"))]
+mod local {
+    use std::io;
+    use std::pin::pin;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::{self, Thread};
+
+    /// Polls one future at a time, and parks the thread while it waits to be woken
+    pub(crate) struct Executor {
+        signal: Arc<Signal>,
+        waker: Waker,
+    }
+
+    struct Signal {
+        woken: AtomicBool,
+        thread: Thread,
+    }
+
+    impl Wake for Signal {
+        fn wake(self: Arc<Self>) {
+            self.wake_by_ref();
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            if !self.woken.swap(true, Ordering::Release) {
+                self.thread.unpark();
+            }
+        }
+    }
+
+    impl Executor {
+        /// Create an executor that runs futures on the calling thread.
+        #[allow(clippy::unnecessary_wraps)]
+        pub(crate) fn new() -> io::Result<Self> {
+            let signal = Arc::new(Signal {
+                woken: AtomicBool::new(false),
+                thread: thread::current(),
+            });
+            let waker = Waker::from(signal.clone());
+            Ok(Self { signal, waker })
+        }
+
+        pub(crate) fn block_on<F: Future>(&self, future: F) -> F::Output {
+            let mut future = pin!(future);
+            let mut context = Context::from_waker(&self.waker);
+            loop {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                    return output;
+                }
+                while !self.signal.woken.swap(false, Ordering::Acquire) {
+                    thread::park();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+mod tokio_runtime {
+    use std::io;
+    use tokio::runtime::{Builder, Runtime};
+
+    pub(crate) struct Executor {
+        runtime: Runtime,
+    }
+
+    impl Executor {
+        pub(crate) fn new() -> io::Result<Self> {
+            let runtime = Builder::new_current_thread().enable_all().build()?;
+            Ok(Self { runtime })
+        }
+
+        pub(crate) fn block_on<F: Future>(&self, future: F) -> F::Output {
+            self.runtime.block_on(future)
+        }
+    }
+}
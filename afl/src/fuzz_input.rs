@@ -0,0 +1,133 @@
+//! How typed fuzz targets (`fuzz!(|value: T| ...)`) decode their inputs
+
+use arbitrary::{Arbitrary, Unstructured};
+use std::marker::PhantomData;
+
+/// A type that a fuzz target's input can be decoded to
+///
+/// The `fuzz!` macros decode each input to the type of the closure's argument. A type that
+/// implements `FuzzInput` is decoded with it, and any other type with [`Arbitrary`]. Implementations
+/// are provided for:
+///
+/// - `&str` and `String`, which accept only inputs that are valid UTF-8
+/// - [`TakeRest<T>`], which decodes with [`Arbitrary::arbitrary_take_rest`]
+/// - `Json<T>`, `Postcard<T>`, and `Bincode<T>`, which decode with serde, behind the `json`,
+///   `postcard`, and `bincode` features
+///
+/// ```rust,no_run
+/// # #[macro_use] extern crate afl;
+/// # fn main() {
+/// fuzz!(|s: &str| {
+///     let _ = s.parse::<f64>();
+/// });
+/// # }
+/// ```
+pub trait FuzzInput<'a>: Sized {
+    /// Decode `data`, or return `None` if it is not a valid encoding, in which case the fuzz
+    /// target does not run.
+    fn decode(data: &'a [u8]) -> Option<Self>;
+}
+
+impl<'a> FuzzInput<'a> for &'a str {
+    fn decode(data: &'a [u8]) -> Option<Self> {
+        std::str::from_utf8(data).ok()
+    }
+}
+
+impl FuzzInput<'_> for String {
+    fn decode(data: &[u8]) -> Option<Self> {
+        <&str>::decode(data).map(ToOwned::to_owned)
+    }
+}
+
+/// A value decoded with [`Arbitrary::arbitrary_take_rest`], which gives the last field of a struct
+/// (e.g., a `Vec`) the rest of the input, rather than a length read from it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TakeRest<T>(pub T);
+
+impl<'a, T: Arbitrary<'a>> FuzzInput<'a> for TakeRest<T> {
+    fn decode(data: &'a [u8]) -> Option<Self> {
+        T::arbitrary_take_rest(Unstructured::new(data))
+            .ok()
+            .map(Self)
+    }
+}
+
+/// A JSON document of type `T`
+#[cfg(feature = "json")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Json<T>(pub T);
+
+#[cfg(feature = "json")]
+impl<'a, T: serde::Deserialize<'a>> FuzzInput<'a> for Json<T> {
+    fn decode(data: &'a [u8]) -> Option<Self> {
+        serde_json::from_slice(data).ok().map(Self)
+    }
+}
+
+/// A value of type `T` in postcard's format
+#[cfg(feature = "postcard")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Postcard<T>(pub T);
+
+#[cfg(feature = "postcard")]
+impl<'a, T: serde::Deserialize<'a>> FuzzInput<'a> for Postcard<T> {
+    fn decode(data: &'a [u8]) -> Option<Self> {
+        postcard::from_bytes(data).ok().map(Self)
+    }
+}
+
+/// A value of type `T` in bincode's format, with its standard configuration
+#[cfg(feature = "bincode")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bincode<T>(pub T);
+
+#[cfg(feature = "bincode")]
+impl<'a, T: serde::Deserialize<'a>> FuzzInput<'a> for Bincode<T> {
+    fn decode(data: &'a [u8]) -> Option<Self> {
+        bincode::serde::borrow_decode_from_slice(data, bincode::config::standard())
+            .ok()
+            .map(|(value, _)| Self(value))
+    }
+}
+
+/// Selects how the `fuzz!` macros decode an input to `T`: with [`FuzzInput`] if `T` implements it,
+/// and otherwise with [`Arbitrary`]. The macros call `decode` on a `&&__Decode<T>`, for which method
+/// resolution tries [`__DecodeFuzzInput`] before dereferencing to reach [`__DecodeArbitrary`].
+#[doc(hidden)]
+pub struct __Decode<T>(PhantomData<T>);
+
+impl<T> __Decode<T> {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<T> Default for __Decode<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[doc(hidden)]
+pub trait __DecodeFuzzInput<'a, T> {
+    fn decode(self, data: &'a [u8]) -> Option<T>;
+}
+
+impl<'a, T: FuzzInput<'a>> __DecodeFuzzInput<'a, T> for &&__Decode<T> {
+    fn decode(self, data: &'a [u8]) -> Option<T> {
+        T::decode(data)
+    }
+}
+
+#[doc(hidden)]
+pub trait __DecodeArbitrary<'a, T> {
+    fn decode(self, data: &'a [u8]) -> Option<T>;
+}
+
+impl<'a, T: Arbitrary<'a>> __DecodeArbitrary<'a, T> for &__Decode<T> {
+    fn decode(self, data: &'a [u8]) -> Option<T> {
+        T::arbitrary(&mut Unstructured::new(data)).ok()
+    }
+}
@@ -0,0 +1,668 @@
+//! The [`Fuzzer`] builder, which the `fuzz!` macros and the `fuzz*` functions are built on
+
+use crate::{
+    Corpus, FuzzOutcome, alloc, crash_record,
+    executor::Executor,
+    inputs::Inputs,
+    watchdog::{self, Watchdog},
+};
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::io;
+use std::panic::{self, AssertUnwindSafe, RefUnwindSafe};
+use std::time::Duration;
+
+const PANIC_HOOK_ENV_VAR: &str = "AFL_RS_PANIC_HOOK";
+const LOOP_COUNT_ENV_VAR: &str = "AFL_FUZZER_LOOPCOUNT";
+const MAX_LEN_ENV_VAR: &str = "AFL_RS_MAX_LEN";
+const ERRORS_ARE_CRASHES_ENV_VAR: &str = "AFL_RS_ERRORS_ARE_CRASHES";
+const WATCHDOG_ENV_VAR: &str = "AFL_RS_WATCHDOG";
+const TIMEOUT_ENV_VAR: &str = "AFL_RS_TIMEOUT";
+const DETECT_LEAKS_ENV_VAR: &str = "AFL_RS_DETECT_LEAKS";
+const LEAK_THRESHOLD_ENV_VAR: &str = "AFL_RS_LEAK_THRESHOLD";
+
+/// Configures and runs a fuzz target
+///
+/// Every option has a default, and an environment variable that overrides the value set in code:
+///
+/// | Option | Default | Environment variable |
+/// |---|---|---|
+/// | [`panic_hook`](Fuzzer::panic_hook) | `true` | `AFL_RS_PANIC_HOOK` (`0` or `1`) |
+/// | [`loop_count`](Fuzzer::loop_count) | `usize::MAX` | `AFL_FUZZER_LOOPCOUNT` |
+/// | [`max_len`](Fuzzer::max_len) | none | `AFL_RS_MAX_LEN` |
+/// | [`errors_are_crashes`](Fuzzer::errors_are_crashes) | `false` | `AFL_RS_ERRORS_ARE_CRASHES` (`0` or `1`) |
+/// | [`watchdog`](Fuzzer::watchdog) | `false` | `AFL_RS_WATCHDOG` (`0` or `1`) |
+/// | [`timeout`](Fuzzer::timeout) | 90% of afl-fuzz's `-t`, if given | `AFL_RS_TIMEOUT` (milliseconds) |
+/// | [`detect_leaks`](Fuzzer::detect_leaks) | `false` | `AFL_RS_DETECT_LEAKS` (`0` or `1`) |
+/// | [`leak_threshold`](Fuzzer::leak_threshold) | `0` | `AFL_RS_LEAK_THRESHOLD` (bytes) |
+///
+/// ```rust,no_run
+/// # extern crate afl;
+/// # use std::sync::Mutex;
+/// # static CACHE: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+/// # fn main() -> Result<(), afl::Error> {
+/// afl::Fuzzer::new()
+///     .max_len(4096)
+///     .reset(|| *CACHE.lock().unwrap() = None)
+///     .run(|data| {
+///         if data.first() == Some(&b'a') {
+///             panic!("BOOM")
+///         }
+///     })
+/// # }
+/// ```
+#[must_use = "a `Fuzzer` does nothing until `run` is called"]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Fuzzer<'a, S = ()> {
+    panic_hook: bool,
+    loop_count: usize,
+    max_len: Option<usize>,
+    errors_are_crashes: bool,
+    watchdog: bool,
+    timeout: Option<Duration>,
+    detect_leaks: bool,
+    leak_threshold: usize,
+    reset: Option<Box<dyn FnMut() + 'a>>,
+    init: Box<dyn FnOnce() -> S + 'a>,
+}
+
+impl Default for Fuzzer<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Fuzzer<'_> {
+    pub fn new() -> Self {
+        Self {
+            panic_hook: true,
+            loop_count: usize::MAX,
+            max_len: None,
+            errors_are_crashes: false,
+            watchdog: false,
+            timeout: None,
+            detect_leaks: false,
+            leak_threshold: 0,
+            reset: None,
+            init: Box::new(|| ()),
+        }
+    }
+}
+
+impl<'a, S> Fuzzer<'a, S> {
+    /// Whether to install a panic hook that aborts the process, so that panics caught inside the
+    /// fuzzed code are still reported as crashes. Overridden by `AFL_RS_PANIC_HOOK`.
+    pub fn panic_hook(mut self, panic_hook: bool) -> Self {
+        self.panic_hook = panic_hook;
+        self
+    }
+
+    /// How many inputs a process runs in persistent mode before afl-fuzz restarts it. Overridden by
+    /// `AFL_FUZZER_LOOPCOUNT`.
+    pub fn loop_count(mut self, loop_count: usize) -> Self {
+        self.loop_count = loop_count;
+        self
+    }
+
+    /// Truncate inputs to at most `max_len` bytes. Overridden by `AFL_RS_MAX_LEN`.
+    pub fn max_len(mut self, max_len: usize) -> Self {
+        self.max_len = Some(max_len);
+        self
+    }
+
+    /// Whether an `Err` returned by the fuzz target is a crash. If not, it is treated like
+    /// [`Corpus::Keep`]. Overridden by `AFL_RS_ERRORS_ARE_CRASHES`.
+    pub fn errors_are_crashes(mut self, errors_are_crashes: bool) -> Self {
+        self.errors_are_crashes = errors_are_crashes;
+        self
+    }
+
+    /// Whether to abort, printing a backtrace and the length of the input, when an iteration runs
+    /// longer than [`timeout`](Fuzzer::timeout). This turns hangs, which afl-fuzz reports without
+    /// any detail, into crashes that can be diagnosed by replaying the input. Overridden by
+    /// `AFL_RS_WATCHDOG`.
+    ///
+    /// The watchdog uses `SIGALRM`, so it cannot be combined with fuzzed code that does.
+    pub fn watchdog(mut self, watchdog: bool) -> Self {
+        self.watchdog = watchdog;
+        self
+    }
+
+    /// How long an iteration may run before the [`watchdog`](Fuzzer::watchdog) aborts it. The
+    /// default is just under afl-fuzz's timeout, as passed to `cargo afl fuzz -t`. Without either,
+    /// the watchdog is not armed, as the timeout that afl-fuzz calibrates itself is not known.
+    /// Overridden by `AFL_RS_TIMEOUT`, in milliseconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Whether to report as a crash any input whose iteration, including the
+    /// [`reset`](Fuzzer::reset) closure, does not free more than
+    /// [`leak_threshold`](Fuzzer::leak_threshold) bytes that it allocated. The report includes
+    /// where the leaked memory was allocated. Overridden by `AFL_RS_DETECT_LEAKS`.
+    ///
+    /// In persistent mode, leaks go unnoticed until the process runs out of memory, far from the
+    /// input that caused them. This finds them without `-Zsanitizer=leak`, but requires
+    /// [`alloc::LimitingAllocator`] to be the global allocator, and slows each allocation down.
+    pub fn detect_leaks(mut self, detect_leaks: bool) -> Self {
+        self.detect_leaks = detect_leaks;
+        self
+    }
+
+    /// How many bytes an iteration may retain before [`detect_leaks`](Fuzzer::detect_leaks)
+    /// reports it. Overridden by `AFL_RS_LEAK_THRESHOLD`.
+    pub fn leak_threshold(mut self, leak_threshold: usize) -> Self {
+        self.leak_threshold = leak_threshold;
+        self
+    }
+
+    /// Call `reset` after each successful iteration.
+    ///
+    /// This is useful when the fuzz target uses static state (e.g., `OnceLock`, `lazy_static`)
+    /// that must be cleared between iterations in AFL++ persistent mode. Without resetting, code
+    /// paths that run only on the first iteration cause AFL's stability metric to drop. The hooks
+    /// that libraries register with [`afl::reset`](crate::reset) are called after `reset`.
+    pub fn reset(mut self, reset: impl FnMut() + 'a) -> Self {
+        self.reset = Some(Box::new(reset));
+        self
+    }
+
+    /// Call `init` exactly once, before the forkserver starts, and pass what it returns to each
+    /// iteration of [`run_with_state`](Fuzzer::run_with_state).
+    ///
+    /// Expensive setup (e.g., loading a grammar or building lookup tables) done here is inherited
+    /// by every process afl-fuzz forks, instead of being repeated in each of them.
+    ///
+    /// ```rust,no_run
+    /// # extern crate afl;
+    /// # fn main() -> Result<(), afl::Error> {
+    /// afl::Fuzzer::new()
+    ///     .init(|| vec!["GET", "POST", "PUT"])
+    ///     .run_with_state(|methods, data| {
+    ///         if let Ok(s) = std::str::from_utf8(data) {
+    ///             let _ = methods.contains(&s);
+    ///         }
+    ///     })
+    /// # }
+    /// ```
+    pub fn init<T>(self, init: impl FnOnce() -> T + 'a) -> Fuzzer<'a, T> {
+        Fuzzer {
+            panic_hook: self.panic_hook,
+            loop_count: self.loop_count,
+            max_len: self.max_len,
+            errors_are_crashes: self.errors_are_crashes,
+            watchdog: self.watchdog,
+            timeout: self.timeout,
+            detect_leaks: self.detect_leaks,
+            leak_threshold: self.leak_threshold,
+            reset: self.reset,
+            init: Box::new(init),
+        }
+    }
+
+    /// Fuzz `closure` by passing it each input that afl-fuzz provides.
+    ///
+    /// `closure` can return `()`, a [`Corpus`] verdict, or a `Result` of either (see
+    /// [`FuzzOutcome`]).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, before any input is run, if an option or an environment variable that
+    /// overrides one has an invalid value.
+    pub fn run<F, O>(self, mut closure: F) -> Result<(), Error>
+    where
+        F: FnMut(&[u8]) -> O + RefUnwindSafe,
+        O: FuzzOutcome,
+    {
+        self.run_with_state(move |_, data| closure(data))
+    }
+
+    /// Like [`run`](Fuzzer::run), but also pass `closure` a reference to the value that the
+    /// [`init`](Fuzzer::init) closure returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, before any input is run, if an option or an environment variable that
+    /// overrides one has an invalid value.
+    pub fn run_with_state<F, O>(self, mut closure: F) -> Result<(), Error>
+    where
+        F: FnMut(&S, &[u8]) -> O + RefUnwindSafe,
+        O: FuzzOutcome,
+    {
+        let Self {
+            panic_hook,
+            loop_count,
+            max_len,
+            errors_are_crashes,
+            watchdog,
+            timeout,
+            detect_leaks,
+            leak_threshold,
+            mut reset,
+            init,
+        } = self.apply_env_overrides()?;
+        let timeout = timeout.or_else(watchdog::default_timeout);
+
+        if detect_leaks {
+            alloc::enable_tracking();
+        }
+
+        let crash_records = crash_record::enabled();
+
+        if panic_hook {
+            let prev_hook = panic::take_hook();
+            // sets panic hook to abort
+            panic::set_hook(Box::new(move |panic_info| {
+                prev_hook(panic_info);
+                crash_record::write_panic(panic_info);
+                std::process::abort();
+            }));
+        }
+
+        let state = init();
+
+        let watchdog = timeout.filter(|_| watchdog).map(Watchdog::install);
+
+        // initialize forkserver there
+        let mut inputs = Inputs::new(loop_count);
+
+        while let Some(input) = inputs.next() {
+            if crash_records {
+                crash_record::start_iteration(input);
+            }
+            let input = &input[..max_len.map_or(input.len(), |max_len| max_len.min(input.len()))];
+
+            if let Some(watchdog) = &watchdog {
+                watchdog.arm(input.len());
+            }
+            if detect_leaks {
+                alloc::start_leak_check();
+            }
+
+            // We still catch unwinding panics just in case the fuzzed code modifies
+            // the panic hook.
+            // If so, the fuzzer will be unable to tell different bugs apart and you will
+            // only be able to find one bug at a time before fixing it to then find a new one.
+            let mut corpus = Corpus::Keep;
+            let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                match closure(&state, input).into_result() {
+                    Ok(verdict) => corpus = verdict,
+                    Err(error) if errors_are_crashes => {
+                        panic!("the fuzz target returned an error: {error}")
+                    }
+                    Err(_) => {}
+                }
+            }));
+
+            if let Err(payload) = result {
+                // hopefully the custom panic hook will be called before and abort the
+                // process before the stack frames are unwinded.
+                if crash_records {
+                    crash_record::write(crash_record::panic_message(&*payload), None, None);
+                }
+                std::process::abort();
+            }
+
+            if let Some(reset) = &mut reset {
+                reset();
+            }
+            crate::reset::run();
+
+            if detect_leaks {
+                alloc::check_leaks(leak_threshold);
+            }
+
+            if let Some(watchdog) = &watchdog {
+                watchdog.disarm();
+            }
+
+            if corpus == Corpus::Reject {
+                inputs.reject();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Like [`run`](Fuzzer::run), but for an async closure. Its futures are run to completion, one
+    /// per input, on an executor that is created once, before the forkserver starts. A future that
+    /// never completes is a hang.
+    ///
+    /// The executor is a minimal one that runs futures on the fuzzing thread, or, with the `tokio`
+    /// feature, a current-thread tokio runtime with I/O and timers enabled.
+    ///
+    /// ```rust,no_run
+    /// # extern crate afl;
+    /// # async fn parse(_: &[u8]) {}
+    /// # fn main() -> Result<(), afl::Error> {
+    /// afl::Fuzzer::new().run_async(async |data| parse(data).await)
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, before any input is run, if an option or an environment variable that
+    /// overrides one has an invalid value, or if the executor cannot be created.
+    pub fn run_async<F, O>(self, mut closure: F) -> Result<(), Error>
+    where
+        F: AsyncFnMut(&[u8]) -> O + RefUnwindSafe,
+        O: FuzzOutcome,
+    {
+        self.run_async_with_state(async move |_, data| closure(data).await)
+    }
+
+    /// Like [`run_async`](Fuzzer::run_async), but also pass `closure` a reference to the value that
+    /// the [`init`](Fuzzer::init) closure returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, before any input is run, if an option or an environment variable that
+    /// overrides one has an invalid value, or if the executor cannot be created.
+    pub fn run_async_with_state<F, O>(self, mut closure: F) -> Result<(), Error>
+    where
+        F: AsyncFnMut(&S, &[u8]) -> O + RefUnwindSafe,
+        O: FuzzOutcome,
+    {
+        // A panic aborts the process, so the executor is never observed after one.
+        let executor = AssertUnwindSafe(Executor::new().map_err(Error::Executor)?);
+        self.run_with_state(move |state, data| executor.block_on(closure(state, data)))
+    }
+
+    /// Remove the [`reset`](Fuzzer::reset) closure, for a caller that runs it itself (e.g., between
+    /// the runs of one iteration).
+    pub(crate) fn take_reset(&mut self) -> Option<Box<dyn FnMut() + 'a>> {
+        self.reset.take()
+    }
+
+    /// Replace each option that an environment variable overrides, and validate the result.
+    fn apply_env_overrides(self) -> Result<Self, Error> {
+        self.apply_overrides(|name| env::var_os(name))
+    }
+
+    /// Like [`apply_env_overrides`](Fuzzer::apply_env_overrides), but with the environment
+    /// variables looked up by `var`
+    fn apply_overrides(mut self, var: impl Fn(&str) -> Option<OsString>) -> Result<Self, Error> {
+        if let Some(panic_hook) = override_with(&var, PANIC_HOOK_ENV_VAR, "`0` or `1`", parse_bool)?
+        {
+            self.panic_hook = panic_hook;
+        }
+        if let Some(loop_count) = override_with(
+            &var,
+            LOOP_COUNT_ENV_VAR,
+            "a positive integer",
+            parse_positive,
+        )? {
+            self.loop_count = loop_count;
+        }
+        if let Some(max_len) =
+            override_with(&var, MAX_LEN_ENV_VAR, "a positive integer", parse_positive)?
+        {
+            self.max_len = Some(max_len);
+        }
+        if let Some(errors_are_crashes) =
+            override_with(&var, ERRORS_ARE_CRASHES_ENV_VAR, "`0` or `1`", parse_bool)?
+        {
+            self.errors_are_crashes = errors_are_crashes;
+        }
+        if let Some(watchdog) = override_with(&var, WATCHDOG_ENV_VAR, "`0` or `1`", parse_bool)? {
+            self.watchdog = watchdog;
+        }
+        if let Some(millis) =
+            override_with(&var, TIMEOUT_ENV_VAR, "a positive integer", parse_positive)?
+        {
+            self.timeout = Some(Duration::from_millis(millis as u64));
+        }
+        if let Some(detect_leaks) =
+            override_with(&var, DETECT_LEAKS_ENV_VAR, "`0` or `1`", parse_bool)?
+        {
+            self.detect_leaks = detect_leaks;
+        }
+        if let Some(leak_threshold) = override_with(
+            &var,
+            LEAK_THRESHOLD_ENV_VAR,
+            "a non-negative integer",
+            |value| value.parse().ok(),
+        )? {
+            self.leak_threshold = leak_threshold;
+        }
+
+        if self.loop_count == 0 {
+            return Err(Error::InvalidOption {
+                name: "loop_count",
+                reason: "must be positive",
+            });
+        }
+        if self.max_len == Some(0) {
+            return Err(Error::InvalidOption {
+                name: "max_len",
+                reason: "must be positive",
+            });
+        }
+        if self.timeout.is_some_and(|timeout| timeout.is_zero()) {
+            return Err(Error::InvalidOption {
+                name: "timeout",
+                reason: "must be positive",
+            });
+        }
+        if self.detect_leaks && !alloc::is_installed() {
+            return Err(Error::InvalidOption {
+                name: "detect_leaks",
+                reason: "requires `afl::alloc::LimitingAllocator` to be the global allocator",
+            });
+        }
+        Ok(self)
+    }
+}
+
+/// An invalid [`Fuzzer`] configuration
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// An option was set to an invalid value
+    InvalidOption {
+        name: &'static str,
+        reason: &'static str,
+    },
+    /// An environment variable that overrides an option has an invalid value
+    InvalidEnvVar {
+        name: &'static str,
+        value: String,
+        expected: &'static str,
+    },
+    /// The executor for an async fuzz target could not be created
+    Executor(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidOption { name, reason } => write!(f, "`{name}` {reason}"),
+            Self::InvalidEnvVar {
+                name,
+                value,
+                expected,
+            } => write!(f, "`{name}` is `{value}`, but it must be {expected}"),
+            Self::Executor(error) => write!(f, "could not create the async executor: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Report `error` and exit. Used by the `fuzz!` macros, which have no caller to return it to.
+#[doc(hidden)]
+pub fn __exit_on_error(error: &Error) -> ! {
+    eprintln!("afl: {error}");
+    std::process::exit(1);
+}
+
+/// Parse the environment variable `name`, if it is set
+pub(crate) fn env_override<T>(
+    name: &'static str,
+    expected: &'static str,
+    parse: impl FnOnce(&str) -> Option<T>,
+) -> Result<Option<T>, Error> {
+    override_with(&|name| env::var_os(name), name, expected, parse)
+}
+
+/// Parse the variable `name`, as looked up by `var`, if it is set
+fn override_with<T>(
+    var: &impl Fn(&str) -> Option<OsString>,
+    name: &'static str,
+    expected: &'static str,
+    parse: impl FnOnce(&str) -> Option<T>,
+) -> Result<Option<T>, Error> {
+    let Some(value) = var(name) else {
+        return Ok(None);
+    };
+    let value = value.to_string_lossy();
+    parse(&value).map(Some).ok_or_else(|| Error::InvalidEnvVar {
+        name,
+        value: value.into_owned(),
+        expected,
+    })
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "0" => Some(false),
+        "1" => Some(true),
+        _ => None,
+    }
+}
+
+pub(crate) fn parse_positive(value: &str) -> Option<usize> {
+    value.parse().ok().filter(|&value| value != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply<'a>(fuzzer: Fuzzer<'a>, vars: &[(&str, &str)]) -> Result<Fuzzer<'a>, Error> {
+        let vars: Vec<(String, OsString)> = vars
+            .iter()
+            .map(|&(name, value)| (name.to_owned(), value.into()))
+            .collect();
+        fuzzer.apply_overrides(|name| {
+            vars.iter()
+                .find(|(var, _)| var == name)
+                .map(|(_, value)| value.clone())
+        })
+    }
+
+    fn error(fuzzer: Fuzzer<'_>, vars: &[(&str, &str)]) -> String {
+        match apply(fuzzer, vars) {
+            Ok(_) => panic!("expected an error for {vars:?}"),
+            Err(error) => error.to_string(),
+        }
+    }
+
+    #[test]
+    fn options_are_kept_without_overrides() {
+        let fuzzer = Fuzzer::new()
+            .panic_hook(false)
+            .loop_count(5)
+            .max_len(10)
+            .errors_are_crashes(true)
+            .watchdog(true)
+            .timeout(Duration::from_millis(20))
+            .leak_threshold(30);
+        let fuzzer = apply(fuzzer, &[]).unwrap();
+        assert!(!fuzzer.panic_hook);
+        assert_eq!(fuzzer.loop_count, 5);
+        assert_eq!(fuzzer.max_len, Some(10));
+        assert!(fuzzer.errors_are_crashes);
+        assert!(fuzzer.watchdog);
+        assert_eq!(fuzzer.timeout, Some(Duration::from_millis(20)));
+        assert!(!fuzzer.detect_leaks);
+        assert_eq!(fuzzer.leak_threshold, 30);
+    }
+
+    #[test]
+    fn env_vars_override_options() {
+        let fuzzer = Fuzzer::new()
+            .panic_hook(false)
+            .loop_count(5)
+            .max_len(10)
+            .errors_are_crashes(true)
+            .watchdog(true)
+            .timeout(Duration::from_millis(20))
+            .leak_threshold(30);
+        let fuzzer = apply(
+            fuzzer,
+            &[
+                (PANIC_HOOK_ENV_VAR, "1"),
+                (LOOP_COUNT_ENV_VAR, "7"),
+                (MAX_LEN_ENV_VAR, "11"),
+                (ERRORS_ARE_CRASHES_ENV_VAR, "0"),
+                (WATCHDOG_ENV_VAR, "0"),
+                (TIMEOUT_ENV_VAR, "21"),
+                (DETECT_LEAKS_ENV_VAR, "0"),
+                (LEAK_THRESHOLD_ENV_VAR, "0"),
+            ],
+        )
+        .unwrap();
+        assert!(fuzzer.panic_hook);
+        assert_eq!(fuzzer.loop_count, 7);
+        assert_eq!(fuzzer.max_len, Some(11));
+        assert!(!fuzzer.errors_are_crashes);
+        assert!(!fuzzer.watchdog);
+        assert_eq!(fuzzer.timeout, Some(Duration::from_millis(21)));
+        assert!(!fuzzer.detect_leaks);
+        assert_eq!(fuzzer.leak_threshold, 0);
+    }
+
+    #[test]
+    fn env_vars_with_invalid_values_are_rejected() {
+        for (name, value, expected) in [
+            (PANIC_HOOK_ENV_VAR, "true", "`0` or `1`"),
+            (LOOP_COUNT_ENV_VAR, "0", "a positive integer"),
+            (MAX_LEN_ENV_VAR, "-1", "a positive integer"),
+            (ERRORS_ARE_CRASHES_ENV_VAR, "", "`0` or `1`"),
+            (WATCHDOG_ENV_VAR, "yes", "`0` or `1`"),
+            (TIMEOUT_ENV_VAR, "1s", "a positive integer"),
+            (DETECT_LEAKS_ENV_VAR, "2", "`0` or `1`"),
+            (LEAK_THRESHOLD_ENV_VAR, "-1", "a non-negative integer"),
+        ] {
+            assert_eq!(
+                error(Fuzzer::new(), &[(name, value)]),
+                format!("`{name}` is `{value}`, but it must be {expected}"),
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_options_are_rejected() {
+        assert_eq!(
+            error(Fuzzer::new().loop_count(0), &[]),
+            "`loop_count` must be positive"
+        );
+        assert_eq!(
+            error(Fuzzer::new().max_len(0), &[]),
+            "`max_len` must be positive"
+        );
+        assert_eq!(
+            error(Fuzzer::new().timeout(Duration::ZERO), &[]),
+            "`timeout` must be positive"
+        );
+        // The test binary does not use `LimitingAllocator`.
+        assert_eq!(
+            error(Fuzzer::new(), &[(DETECT_LEAKS_ENV_VAR, "1")]),
+            "`detect_leaks` requires `afl::alloc::LimitingAllocator` to be the global allocator"
+        );
+        // An override can fix an invalid option.
+        assert!(apply(Fuzzer::new().loop_count(0), &[(LOOP_COUNT_ENV_VAR, "1")]).is_ok());
+    }
+
+    #[test]
+    fn executor_errors_are_displayed() {
+        let error = Error::Executor(io::Error::other("no threads"));
+        assert_eq!(
+            error.to_string(),
+            "could not create the async executor: no threads"
+        );
+    }
+}
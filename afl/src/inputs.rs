@@ -0,0 +1,227 @@
+//! The low-level API that [`Fuzzer`](crate::Fuzzer) is built on
+
+use crate::{
+    __afl_area_ptr, __afl_fuzz_len, __afl_fuzz_ptr, __afl_manual_init, __afl_map_size,
+    __afl_persistent_loop, __exit_on_error, alloc, dictionary,
+    fuzzer::{env_override, parse_positive},
+    targets,
+};
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+const MALLOC_LIMIT_MB_ENV_VAR: &str = "AFL_RS_MALLOC_LIMIT_MB";
+
+/// Start the forkserver, if the program is running under afl-fuzz.
+///
+/// Everything before this call runs once; everything after it runs in each process afl-fuzz forks.
+/// So expensive setup that does not depend on the input should come before it. Calling `init` more
+/// than once has no effect. [`inputs`] calls it.
+pub fn init() {
+    embed_markers();
+    dictionary::install();
+    unsafe { __afl_manual_init() };
+}
+
+/// Call [`init`] and return the testcases to run.
+///
+/// Under afl-fuzz, the testcases come from shared memory, in a persistent loop. Otherwise, each
+/// command-line argument is read as a file, or, if there are none, standard input is read. The
+/// latter makes it easy to reproduce a crash: `target/debug/my_target out/default/crashes/id:...`.
+///
+/// `Inputs` is not an [`Iterator`], because each testcase borrows it: afl-fuzz writes the next
+/// testcase over the current one. Use `while let`:
+///
+/// ```rust,no_run
+/// # extern crate afl;
+/// # fn main() {
+/// std::panic::set_hook(Box::new(|_| std::process::abort()));
+/// let mut inputs = afl::inputs();
+/// while let Some(data) = inputs.next() {
+///     if data.first() == Some(&b'a') {
+///         panic!("BOOM")
+///     }
+/// }
+/// # }
+/// ```
+///
+/// Unlike the `fuzz!` macros, `inputs` does not turn panics into crashes; afl-fuzz only sees a
+/// crash if the process dies from a signal. Build with `panic = "abort"` or install a panic hook
+/// that aborts, as above.
+#[must_use]
+pub fn inputs() -> Inputs {
+    Inputs::new(usize::MAX)
+}
+
+/// Testcases returned by [`inputs`]
+pub struct Inputs {
+    source: Source,
+    buffer: Vec<u8>,
+    /// Whether [`Inputs::reject`] was called for the current testcase
+    rejected: bool,
+}
+
+enum Source {
+    /// afl-fuzz writes testcases to shared memory; `None` once the persistent loop has ended
+    SharedMemory(Option<usize>),
+    Files(std::vec::IntoIter<OsString>),
+    Stdin,
+    Done,
+}
+
+impl Inputs {
+    /// Like [`inputs`], but restart after `loop_count` testcases in persistent mode
+    pub(crate) fn new(loop_count: usize) -> Self {
+        if let Some(limit_mb) = env_override(
+            MALLOC_LIMIT_MB_ENV_VAR,
+            "a positive integer",
+            parse_positive,
+        )
+        .unwrap_or_else(|error| __exit_on_error(&error))
+        {
+            alloc::set_limit_mb(limit_mb);
+        }
+        init();
+        let source = if unsafe { !__afl_fuzz_ptr.is_null() } {
+            Source::SharedMemory(Some(loop_count))
+        } else {
+            let paths = env::args_os()
+                .skip(1 + targets::args_consumed())
+                .collect::<Vec<_>>();
+            if paths.is_empty() {
+                Source::Stdin
+            } else {
+                Source::Files(paths.into_iter())
+            }
+        };
+        Self {
+            source,
+            buffer: Vec::new(),
+            rejected: false,
+        }
+    }
+
+    /// Return the next testcase, or `None` if there are no more.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<&[u8]> {
+        alloc::start_iteration();
+        match &mut self.source {
+            Source::SharedMemory(loop_count) => {
+                let count = (*loop_count)?;
+                // Discard the coverage as late as possible, so that the code that runs between
+                // `reject` and the end of the run does not leave any behind. As `aflpp_driver`
+                // does, keep the first byte set, or afl-fuzz stops with "No instrumentation
+                // detected" if a seed input is rejected.
+                if std::mem::take(&mut self.rejected) {
+                    unsafe {
+                        std::ptr::write_bytes(__afl_area_ptr, 0, __afl_map_size as usize);
+                        *__afl_area_ptr = 1;
+                    }
+                }
+                if unsafe { __afl_persistent_loop(count) } == 0 {
+                    *loop_count = None;
+                    return None;
+                }
+                // get the testcase from the shared memory
+                Some(unsafe {
+                    let input_len = *__afl_fuzz_len as usize;
+                    std::slice::from_raw_parts(__afl_fuzz_ptr, input_len)
+                })
+            }
+            Source::Files(paths) => {
+                for path in paths.by_ref() {
+                    match fs::read(&path) {
+                        Ok(contents) => {
+                            self.buffer = contents;
+                            return Some(&self.buffer);
+                        }
+                        Err(error) => {
+                            eprintln!(
+                                "afl: could not read `{}`: {error}",
+                                Path::new(&path).display()
+                            );
+                        }
+                    }
+                }
+                None
+            }
+            Source::Stdin => {
+                self.source = Source::Done;
+                self.buffer.clear();
+                io::stdin().read_to_end(&mut self.buffer).ok()?;
+                Some(&self.buffer)
+            }
+            Source::Done => None,
+        }
+    }
+
+    /// Discard the coverage of the current testcase when the next one is requested, so that
+    /// afl-fuzz does not add it to its queue (see [`Corpus::Reject`](crate::Corpus::Reject)).
+    pub fn reject(&mut self) {
+        self.rejected = true;
+    }
+}
+
+fn embed_markers() {
+    // this marker strings needs to be in the produced executable for
+    // afl-fuzz to detect `persistent mode` and `defered mode`
+    static PERSIST_MARKER: &str = "##SIG_AFL_PERSISTENT##\0";
+    static DEFERED_MARKER: &str = "##SIG_AFL_DEFER_FORKSRV##\0";
+
+    // this marker string lets `cargo afl fuzz` warn when the binary was built
+    // for a CPU that the current host does not support
+    static TARGET_CPU_MARKER: &str = concat!(
+        "##AFL_RS_TARGET_CPU:",
+        env!("AFL_RS_TARGET_CPU"),
+        ":",
+        env!("AFL_RS_TARGET_FEATURES"),
+        "##\0"
+    );
+
+    // we now need a fake instruction to prevent the compiler from optimizing out
+    // those marker strings
+    unsafe { std::ptr::read_volatile(&raw const PERSIST_MARKER) }; // hack used in https://github.com/bluss/bencher for black_box()
+    unsafe { std::ptr::read_volatile(&raw const DEFERED_MARKER) };
+    unsafe { std::ptr::read_volatile(&raw const TARGET_CPU_MARKER) };
+    // unsafe { asm!("" : : "r"(&PERSIST_MARKER)) }; // hack used in nightly's back_box(), requires feature asm
+    // unsafe { asm!("" : : "r"(&DEFERED_MARKER)) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_files(paths: &[&Path]) -> Inputs {
+        Inputs {
+            source: Source::Files(
+                paths
+                    .iter()
+                    .map(|path| path.as_os_str().to_owned())
+                    .collect::<Vec<_>>()
+                    .into_iter(),
+            ),
+            buffer: Vec::new(),
+            rejected: false,
+        }
+    }
+
+    #[test]
+    fn files_are_read_in_order_and_unreadable_ones_skipped() {
+        let dir = std::env::temp_dir().join(format!("afl-inputs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (first, second) = (dir.join("first"), dir.join("second"));
+        fs::write(&first, "xyz").unwrap();
+        fs::write(&second, "").unwrap();
+
+        let mut inputs = from_files(&[&first, &dir.join("missing"), &second]);
+        assert_eq!(inputs.next(), Some(&b"xyz"[..]));
+        inputs.reject();
+        assert_eq!(inputs.next(), Some(&b""[..]));
+        assert_eq!(inputs.next(), None);
+        assert_eq!(inputs.next(), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -5,21 +5,74 @@
 // you may not use this file except in compliance with the License.
 // See `LICENSE` in this repository.
 
-use std::env;
-use std::io::{self, Read};
-use std::os::raw::c_char;
-use std::panic;
+pub mod alloc;
+mod command_line;
+mod corpus;
+mod crash_record;
+mod dictionary;
+mod differential;
+mod executor;
+mod fuzz_input;
+mod fuzzer;
+mod inputs;
+pub mod reset;
+#[cfg(all(feature = "rust_runtime", unix))]
+mod runtime;
+pub mod stateful;
+mod targets;
+mod watchdog;
+
+pub use command_line::CommandLine;
+pub use corpus::{Corpus, FuzzOutcome};
+#[cfg(feature = "bincode")]
+pub use fuzz_input::Bincode;
+#[cfg(feature = "json")]
+pub use fuzz_input::Json;
+#[cfg(feature = "postcard")]
+pub use fuzz_input::Postcard;
+pub use fuzz_input::{FuzzInput, TakeRest};
+pub use fuzzer::{Error, Fuzzer};
+pub use inputs::{Inputs, init, inputs};
+
+/// The `arbitrary` crate, which typed fuzz targets decode their inputs with by default
+pub use arbitrary;
+
+/// Register a function to reset static state after each iteration; see [`reset`].
+pub use afl_macros::on_reset;
 
-// those functions are provided by the afl-compiler-rt static library
-unsafe extern "C" {
-    fn __afl_persistent_loop(counter: usize) -> isize;
-    fn __afl_manual_init();
-
-    static __afl_fuzz_len: *const u32;
-    static __afl_fuzz_ptr: *const u8;
-}
-
-// AFL++ IJON functions in afl-compiler-rt
+#[doc(hidden)]
+pub use corpus::__Converted;
+#[doc(hidden)]
+pub use crash_record::__context;
+#[doc(hidden)]
+pub use dictionary::{__Token, __encode, __encoded_len};
+#[doc(hidden)]
+pub use differential::__differential;
+#[doc(hidden)]
+pub use fuzz_input::{__Decode, __DecodeArbitrary, __DecodeFuzzInput};
+#[doc(hidden)]
+pub use fuzzer::__exit_on_error;
+#[doc(hidden)]
+pub use targets::__select;
+
+#[cfg(all(feature = "rust_runtime", unix))]
+use runtime::{
+    __afl_area_ptr, __afl_dictionary, __afl_dictionary_len, __afl_fuzz_len, __afl_fuzz_ptr,
+    __afl_manual_init, __afl_map_size, __afl_persistent_loop,
+};
+
+#[cfg(not(any(feature = "c_runtime", feature = "rust_runtime")))]
+compile_error!("the `afl` crate requires either its `c_runtime` or its `rust_runtime` feature");
+
+// those functions are provided by the afl-compiler-rt static library, which `afl-sys` builds
+#[cfg(all(feature = "c_runtime", not(all(feature = "rust_runtime", unix))))]
+use afl_sys::{
+    __afl_area_ptr, __afl_dictionary, __afl_dictionary_len, __afl_fuzz_len, __afl_fuzz_ptr,
+    __afl_manual_init, __afl_map_size, __afl_persistent_loop,
+};
+
+// AFL++ IJON functions in afl-compiler-rt, which the Rust runtime does not implement
+#[cfg(all(feature = "c_runtime", not(all(feature = "rust_runtime", unix))))]
 unsafe extern "C" {
     pub fn ijon_max(addr: u32, val: u64);
     pub fn ijon_min(addr: u32, val: u64);
@@ -29,7 +82,7 @@ unsafe extern "C" {
     pub fn ijon_reset_state();
     pub fn ijon_simple_hash(x: u64) -> u64;
     pub fn ijon_hashint(old: u32, val: u32) -> u32;
-    pub fn ijon_hashstr(old: u32, val: *const c_char) -> u32;
+    pub fn ijon_hashstr(old: u32, val: *const std::os::raw::c_char) -> u32;
     pub fn ijon_hashmen(old: u32, val: *const u8, len: usize) -> u32;
     pub fn ijon_hashstack_backtrace() -> u32;
     pub fn ijon_hashstack() -> u32;
@@ -271,99 +324,24 @@ where
 /// });
 /// # }
 /// ```
-pub fn fuzz_with_reset<F, R>(hook: bool, mut closure: F, mut reset: R)
+pub fn fuzz_with_reset<F, R>(hook: bool, closure: F, reset: R)
 where
     F: FnMut(&[u8]) + std::panic::RefUnwindSafe,
     R: FnMut(),
 {
-    // this marker strings needs to be in the produced executable for
-    // afl-fuzz to detect `persistent mode` and `defered mode`
-    static PERSIST_MARKER: &str = "##SIG_AFL_PERSISTENT##\0";
-    static DEFERED_MARKER: &str = "##SIG_AFL_DEFER_FORKSRV##\0";
-
-    // we now need a fake instruction to prevent the compiler from optimizing out
-    // those marker strings
-    unsafe { std::ptr::read_volatile(&raw const PERSIST_MARKER) }; // hack used in https://github.com/bluss/bencher for black_box()
-    unsafe { std::ptr::read_volatile(&raw const DEFERED_MARKER) };
-    // unsafe { asm!("" : : "r"(&PERSIST_MARKER)) }; // hack used in nightly's back_box(), requires feature asm
-    // unsafe { asm!("" : : "r"(&DEFERED_MARKER)) };
-
-    if hook {
-        let prev_hook = std::panic::take_hook();
-        // sets panic hook to abort
-        std::panic::set_hook(Box::new(move |panic_info| {
-            prev_hook(panic_info);
-            std::process::abort();
-        }));
-    }
-
-    let mut input = vec![];
-
-    let loop_count = if let Ok(value) = env::var("AFL_FUZZER_LOOPCOUNT") {
-        value
-            .parse()
-            .expect("Failed to parse environment variable to a number")
-    } else {
-        usize::MAX
-    };
-
-    // initialize forkserver there
-    unsafe { __afl_manual_init() };
-
-    if unsafe { __afl_fuzz_ptr.is_null() } {
-        // In-memory testcase delivery is not enabled; the target is not running
-        // in a persistent loop, so `reset` is not needed here.
-        // Get buffer from AFL++ through stdin.
-        let result = io::stdin().read_to_end(&mut input);
-        if result.is_err() {
-            return;
-        }
-        let input_ref = &input;
-
-        let did_panic = std::panic::catch_unwind(panic::AssertUnwindSafe(|| {
-            closure(input_ref);
-        }))
-        .is_err();
-
-        if did_panic {
-            // hopefully the custom panic hook will be called before and abort the
-            // process before the stack frames are unwinded.
-            std::process::abort();
-        }
-    } else {
-        while unsafe { __afl_persistent_loop(loop_count) } != 0 {
-            // get the testcase from the fuzzer
-            let input_ref = unsafe {
-                // get the testcase from the shared memory
-                let input_len = *__afl_fuzz_len as usize;
-                std::slice::from_raw_parts(__afl_fuzz_ptr, input_len)
-            };
-
-            // We still catch unwinding panics just in case the fuzzed code modifies
-            // the panic hook.
-            // If so, the fuzzer will be unable to tell different bugs apart and you will
-            // only be able to find one bug at a time before fixing it to then find a new one.
-            let did_panic = std::panic::catch_unwind(panic::AssertUnwindSafe(|| {
-                closure(input_ref);
-            }))
-            .is_err();
-
-            if did_panic {
-                // hopefully the custom panic hook will be called before and abort the
-                // process before the stack frames are unwinded.
-                std::process::abort();
-            }
-
-            input.clear();
-            reset();
-        }
-    }
+    Fuzzer::new()
+        .panic_hook(hook)
+        .reset(reset)
+        .run(closure)
+        .unwrap_or_else(|error| __exit_on_error(&error));
 }
 
 /// Fuzz a closure-like block of code by passing it an object of arbitrary type.
 ///
 /// You can choose the type of the argument using the syntax as in the example below.
-/// Please check out the `arbitrary` crate to see which types are available.
+/// An input that cannot be decoded to that type is skipped and rejected, as if the closure had
+/// returned [`Corpus::Reject`]. Types that implement [`FuzzInput`] (e.g., `&str`, for valid UTF-8)
+/// are decoded with it, and any other type with the `arbitrary` crate, which `afl` re-exports.
 ///
 /// For performance reasons, it is recommended that you use the native type `&[u8]` when possible.
 ///
@@ -382,6 +360,33 @@ where
 /// });
 /// # }
 /// ```
+///
+/// Expensive setup can be done once, before the forkserver starts, with an `init` closure. What it
+/// returns is passed by reference to each iteration:
+///
+/// ```rust,no_run
+/// # #[macro_use] extern crate afl;
+/// # fn main() {
+/// fuzz!(init = || vec!["GET", "POST", "PUT"], |methods, data: &[u8]| {
+///     if let Ok(s) = std::str::from_utf8(data) {
+///         let _ = methods.contains(&s);
+///     }
+/// });
+/// # }
+/// ```
+///
+/// The closure can be async, in which case each iteration's future is run to completion on an
+/// executor that is created once (see [`Fuzzer::run_async`]):
+///
+/// ```rust,no_run
+/// # #[macro_use] extern crate afl;
+/// # async fn decode(_: &[u8]) -> Result<(), ()> { Ok(()) }
+/// # fn main() {
+/// fuzz!(async |data: &[u8]| {
+///     let _ = decode(data).await;
+/// });
+/// # }
+/// ```
 #[macro_export]
 macro_rules! fuzz {
     ( $($x:tt)* ) => { $crate::__fuzz!(true, $($x)*) }
@@ -408,29 +413,96 @@ macro_rules! __reset_or_noop {
 #[doc(hidden)]
 #[macro_export]
 macro_rules! __fuzz {
-    ($hook:expr, |$buf:ident| $body:expr $(, $reset:expr)?) => {
-        $crate::fuzz_with_reset($hook, |$buf| $body, $crate::__reset_or_noop!($($reset)?));
-    };
-    ($hook:expr, |$buf:ident: &[u8]| $body:expr $(, $reset:expr)?) => {
-        $crate::fuzz_with_reset($hook, |$buf| $body, $crate::__reset_or_noop!($($reset)?));
-    };
-    ($hook:expr, |$buf:ident: $dty: ty| $body:expr $(, $reset:expr)?) => {
-        $crate::fuzz_with_reset(
-            $hook,
-            |$buf| {
-                let $buf: $dty = {
-                    let mut data = ::arbitrary::Unstructured::new($buf);
-                    if let Ok(d) = ::arbitrary::Arbitrary::arbitrary(&mut data).map_err(|_| "") {
-                        d
-                    } else {
-                        return;
-                    }
-                };
-
-                $body
-            },
-            $crate::__reset_or_noop!($($reset)?),
-        );
+    ($hook:expr, init = $init:expr, async |$state:tt $(: $sty:ty)?, $($rest:tt)*) => {
+        $crate::__fuzz!(@parse_async $hook, $init, ($state $(: $sty)?), $($rest)*)
+    };
+    ($hook:expr, init = $init:expr, async |$($rest:tt)*) => {
+        $crate::__fuzz!(@parse_async $hook, $init, (_), $($rest)*)
+    };
+    ($hook:expr, async |$($rest:tt)*) => {
+        $crate::__fuzz!(@parse_async $hook, || (), (_), $($rest)*)
+    };
+    ($hook:expr, init = $init:expr, |$state:tt $(: $sty:ty)?, $($rest:tt)*) => {
+        $crate::__fuzz!(@parse $hook, $init, ($state $(: $sty)?), $($rest)*)
+    };
+    ($hook:expr, init = $init:expr, |$($rest:tt)*) => {
+        $crate::__fuzz!(@parse $hook, $init, (_), $($rest)*)
+    };
+    ($hook:expr, |$($rest:tt)*) => {
+        $crate::__fuzz!(@parse $hook, || (), (_), $($rest)*)
+    };
+    (@parse $hook:expr, $init:expr, ($($state:tt)*), $buf:ident $(: &[u8])?| -> $ret:ty $body:block $(, $reset:expr)?) => {
+        $crate::__fuzz!(@run $hook, $init, run_with_state, |$($state)*, $buf: &[u8]| -> $ret { $body } $(, $reset)?)
+    };
+    (@parse $hook:expr, $init:expr, ($($state:tt)*), $buf:ident $(: &[u8])?| $body:expr $(, $reset:expr)?) => {
+        $crate::__fuzz!(@run $hook, $init, run_with_state, |$($state)*, $buf: &[u8]| $body $(, $reset)?)
+    };
+    (@parse $hook:expr, $init:expr, ($($state:tt)*), $buf:ident: $dty:ty| -> $ret:ty $body:block $(, $reset:expr)?) => {
+        $crate::__fuzz!(@arbitrary $hook, $init, ($($state)*), $buf: $dty, (|| -> $ret { $body }) $(, $reset)?)
+    };
+    (@parse $hook:expr, $init:expr, ($($state:tt)*), $buf:ident: $dty:ty| $body:expr $(, $reset:expr)?) => {
+        $crate::__fuzz!(@arbitrary $hook, $init, ($($state)*), $buf: $dty, (|| $body) $(, $reset)?)
+    };
+    (@parse_async $hook:expr, $init:expr, ($($state:tt)*), $buf:ident $(: &[u8])?| -> $ret:ty $body:block $(, $reset:expr)?) => {
+        $crate::__fuzz!(@run $hook, $init, run_async_with_state, async |$($state)*, $buf: &[u8]| -> $ret { $body } $(, $reset)?)
+    };
+    (@parse_async $hook:expr, $init:expr, ($($state:tt)*), $buf:ident $(: &[u8])?| $body:expr $(, $reset:expr)?) => {
+        $crate::__fuzz!(@run $hook, $init, run_async_with_state, async |$($state)*, $buf: &[u8]| $body $(, $reset)?)
+    };
+    (@parse_async $hook:expr, $init:expr, ($($state:tt)*), $buf:ident: $dty:ty| -> $ret:ty $body:block $(, $reset:expr)?) => {
+        $crate::__fuzz!(
+            @run $hook,
+            $init,
+            run_async_with_state,
+            async |$($state)*, $buf: &[u8]| {
+                $crate::__fuzz!(@convert $buf: $dty);
+                $crate::__Converted::Ran((async || -> $ret { $body })().await)
+            }
+            $(, $reset)?
+        )
+    };
+    (@parse_async $hook:expr, $init:expr, ($($state:tt)*), $buf:ident: $dty:ty| $body:expr $(, $reset:expr)?) => {
+        $crate::__fuzz!(
+            @run $hook,
+            $init,
+            run_async_with_state,
+            async |$($state)*, $buf: &[u8]| {
+                $crate::__fuzz!(@convert $buf: $dty);
+                $crate::__Converted::Ran((async || $body)().await)
+            }
+            $(, $reset)?
+        )
+    };
+    (@arbitrary $hook:expr, $init:expr, ($($state:tt)*), $buf:ident: $dty:ty, $body:expr $(, $reset:expr)?) => {
+        $crate::__fuzz!(
+            @run $hook,
+            $init,
+            run_with_state,
+            |$($state)*, $buf: &[u8]| {
+                $crate::__fuzz!(@convert $buf: $dty);
+                $crate::__Converted::Ran($body())
+            }
+            $(, $reset)?
+        )
+    };
+    (@convert $buf:ident: $dty:ty) => {
+        let $buf: $dty = {
+            #[allow(unused_imports)]
+            use $crate::{__DecodeArbitrary as _, __DecodeFuzzInput as _};
+            if let Some(d) = (&&$crate::__Decode::<$dty>::new()).decode($buf) {
+                d
+            } else {
+                return $crate::__Converted::Failed;
+            }
+        };
+    };
+    (@run $hook:expr, $init:expr, $run:ident, $closure:expr $(, $reset:expr)?) => {
+        $crate::Fuzzer::new()
+            .panic_hook($hook)
+            .reset($crate::__reset_or_noop!($($reset)?))
+            .init($init)
+            .$run($closure)
+            .unwrap_or_else(|error| $crate::__exit_on_error(&error));
     };
 }
 
@@ -465,3 +537,183 @@ macro_rules! fuzz_with_reset {
 macro_rules! fuzz_with_reset_nohook {
     ( $($x:tt)* ) => { $crate::__fuzz!(false, $($x)*) }
 }
+
+/// Declare tokens (e.g., keywords or magic numbers) that afl-fuzz should splice into inputs. Takes
+/// string and byte string literals (or other `'static` strings and byte slices) of 1 to 128 bytes.
+///
+/// ```rust,no_run
+/// # #[macro_use] extern crate afl;
+/// afl::dictionary!["SELECT", "FROM", "WHERE", b"\x89PNG"];
+///
+/// # fn main() {
+/// fuzz!(|data: &[u8]| {
+///     let _ = data;
+/// });
+/// # }
+/// ```
+///
+/// The tokens are embedded in the fuzz target, which sends them to afl-fuzz when the forkserver
+/// starts, as AFL++'s autodictionary. `dictionary!` can be used in any crate the fuzz target is
+/// built from, any number of times. It does nothing on targets other than ELF ones (e.g., Linux).
+#[macro_export]
+macro_rules! dictionary {
+    ($($token:expr),* $(,)?) => {
+        const _: () = {
+            const TOKENS: &[&[u8]] = &[$($crate::__Token($token).bytes()),*];
+            #[used]
+            #[cfg_attr(
+                any(
+                    target_os = "android",
+                    target_os = "dragonfly",
+                    target_os = "freebsd",
+                    target_os = "illumos",
+                    target_os = "linux",
+                    target_os = "netbsd",
+                    target_os = "openbsd",
+                ),
+                unsafe(link_section = "afl_rs_dictionary")
+            )]
+            static DICTIONARY: [u8; $crate::__encoded_len(TOKENS)] = $crate::__encode(TOKENS);
+        };
+    };
+}
+
+/// Fuzz a command-line program through its arguments, and optionally its environment variables and
+/// standard input, which are decoded from each input as a [`CommandLine`].
+///
+/// ```rust,no_run
+/// # #[macro_use] extern crate afl;
+/// # #[derive(Debug)] struct Cli;
+/// # impl Cli { fn try_parse_from(_: Vec<std::ffi::OsString>) -> Result<Self, String> { Ok(Cli) } }
+/// # fn run(_: Cli, _: &[u8]) {}
+/// # fn main() {
+/// fuzz_args!(|args, _env, stdin| {
+///     let cli = Cli::try_parse_from(args)?;
+///     run(cli, &stdin);
+///     Ok::<_, String>(())
+/// });
+/// # }
+/// ```
+///
+/// The closure takes the arguments (`Vec<OsString>`, starting with the program name), and
+/// optionally the environment variables (`Vec<(OsString, OsString)>`) and standard input
+/// (`Vec<u8>`). It can return what [`fuzz!`]'s closures can, so an argument parser's error is not a
+/// crash unless [`Fuzzer::errors_are_crashes`] is set.
+#[macro_export]
+macro_rules! fuzz_args {
+    (|$args:pat_param $(,)?| $body:expr) => {
+        $crate::fuzz_args!(|$args, _, _| $body)
+    };
+    (|$args:pat_param, $env:pat_param $(,)?| $body:expr) => {
+        $crate::fuzz_args!(|$args, $env, _| $body)
+    };
+    (|$args:pat_param, $env:pat_param, $stdin:pat_param $(,)?| $body:expr) => {
+        $crate::fuzz!(|command_line: $crate::CommandLine| {
+            let $crate::CommandLine {
+                args: $args,
+                env: $env,
+                stdin: $stdin,
+            } = command_line;
+            $body
+        })
+    };
+}
+
+/// Record context (e.g., what was parsed from the input) that is written, along with the panic
+/// message and backtrace, to the crash record of an input that panics. Takes the same arguments as
+/// [`format!`], which are formatted only if crash records are enabled.
+///
+/// ```rust,no_run
+/// # #[macro_use] extern crate afl;
+/// # fn main() {
+/// fuzz!(|data: &[u8]| {
+///     if let Some((&version, payload)) = data.split_first() {
+///         afl::context!("version {version}, {} bytes of payload", payload.len());
+///     }
+/// });
+/// # }
+/// ```
+///
+/// Crash records are enabled when `AFL_RS_CRASH_RECORDS_DIR` names a directory, which `cargo afl
+/// fuzz` does, and then copies each record to a `crash_records` directory next to `crashes`.
+#[macro_export]
+macro_rules! context {
+    ( $($arg:tt)* ) => {
+        $crate::__context(::core::format_args!($($arg)*))
+    };
+}
+
+/// Fuzz one of several closures, chosen at run time, so that many targets can share one binary.
+///
+/// The target is named by `AFL_RS_TARGET`, or else by the first command-line argument (which is
+/// then not read as an input file). The choice is made before the forkserver starts, so the
+/// processes afl-fuzz forks run only the chosen target, and the others add nothing to the coverage
+/// map.
+///
+/// ```rust,no_run
+/// # #[macro_use] extern crate afl;
+/// # fn main() {
+/// targets! {
+///     utf8 => |data: &[u8]| {
+///         let _ = std::str::from_utf8(data);
+///     },
+///     number => |data: &[u8]| {
+///         if let Ok(s) = std::str::from_utf8(data) {
+///             let _ = s.parse::<u64>();
+///         }
+///     },
+/// }
+/// # }
+/// ```
+///
+/// Run a target with, e.g., `cargo afl fuzz -i in -o out -- target/debug/my_targets utf8` or
+/// `cargo afl run utf8 < input`.
+#[macro_export]
+macro_rules! targets {
+    ( $($name:ident => $closure:expr),+ $(,)? ) => {
+        match $crate::__select(&[$(::core::stringify!($name)),+]) {
+            $(
+                name if name == ::core::stringify!($name) => $crate::Fuzzer::new()
+                    .run($closure)
+                    .unwrap_or_else(|error| $crate::__exit_on_error(&error)),
+            )+
+            _ => ::core::unreachable!(),
+        }
+    };
+}
+
+/// Fuzz two implementations of the same thing (e.g., a rewrite and the reference it replaces)
+/// against each other. Both expressions are evaluated on each input, and their results are compared
+/// with `PartialEq`. If they differ, or if either of them panics, the fuzz target panics with the
+/// input's `Debug` representation, both results, and a line-by-line diff of the two.
+///
+/// ```rust,no_run
+/// # #[macro_use] extern crate afl;
+/// # mod old { pub fn parse(s: &str) -> Option<u32> { s.parse().ok() } }
+/// # mod new { pub fn parse(s: &str) -> Option<u32> { s.parse().ok() } }
+/// # fn main() {
+/// differential!(|input: &str| old::parse(input), new::parse(input));
+/// # }
+/// ```
+///
+/// The argument can be typed as with [`fuzz!`]. Panics are caught so that they can be compared, so
+/// panics that both implementations catch themselves are not crashes, as with [`fuzz_nohook!`].
+#[macro_export]
+macro_rules! differential {
+    (|$input:ident: &[u8]| $left:expr, $right:expr $(,)?) => {
+        $crate::__fuzz!(false, |$input: &[u8]| $crate::differential!(@compare $input, $left, $right))
+    };
+    (|$input:ident| $left:expr, $right:expr $(,)?) => {
+        $crate::__fuzz!(false, |$input| $crate::differential!(@compare $input, $left, $right))
+    };
+    (|$input:ident: $ty:ty| $left:expr, $right:expr $(,)?) => {
+        $crate::__fuzz!(false, |$input: $ty| $crate::differential!(@compare $input, $left, $right))
+    };
+    (@compare $input:ident, $left:expr, $right:expr) => {
+        $crate::__differential(
+            &$input,
+            (::core::stringify!($left), || $left),
+            (::core::stringify!($right), || $right),
+        )
+    };
+}
@@ -0,0 +1,58 @@
+//! Hooks that reset static state after each iteration
+//!
+//! In persistent mode, state that survives an iteration (caches, interners, `OnceLock`s) makes the
+//! next one behave differently, which lowers afl-fuzz's stability. Such state often lives in
+//! dependencies that the fuzz target's author does not control. A library can register a hook that
+//! resets its own state, either at run time with [`register`], or with the
+//! [`on_reset`](crate::on_reset) attribute:
+//!
+//! ```rust
+//! # extern crate afl;
+//! use std::sync::Mutex;
+//!
+//! static INTERNER: Mutex<Vec<String>> = Mutex::new(Vec::new());
+//!
+//! #[afl::on_reset]
+//! fn clear_interner() {
+//!     INTERNER.lock().unwrap().clear();
+//! }
+//! # fn main() {
+//! #     INTERNER.lock().unwrap().push(String::from("interned"));
+//! #     afl::reset::run();
+//! #     assert!(INTERNER.lock().unwrap().is_empty());
+//! # }
+//! ```
+//!
+//! A library that depends on `afl` only when fuzzing can apply the attribute with
+//! `#[cfg_attr(fuzzing, afl::on_reset)]`.
+//!
+//! [`Fuzzer`](crate::Fuzzer) and the `fuzz!` macros call every registered hook after each
+//! iteration, after the `reset` closure. Loops written with [`inputs`](crate::inputs) should call
+//! [`run`].
+
+use std::sync::{Mutex, PoisonError};
+
+static HOOKS: Mutex<Vec<fn()>> = Mutex::new(Vec::new());
+
+/// Register `hook` to be called after each iteration.
+pub fn register(hook: fn()) {
+    HOOKS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(hook);
+}
+
+/// Call every registered hook, in the order they were registered.
+pub fn run() {
+    // The lock is not held while a hook runs, so that hooks can register others.
+    let mut index = 0;
+    while let Some(hook) = HOOKS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(index)
+        .copied()
+    {
+        hook();
+        index += 1;
+    }
+}
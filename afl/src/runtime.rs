@@ -0,0 +1,391 @@
+//! A Rust implementation of the parts of AFL++'s `afl-compiler-rt.o` that afl.rs needs
+//!
+//! Enabled by the `rust_runtime` feature. It provides the forkserver (including AFL++'s option
+//! negotiation), the shared-memory coverage map, the `trace-pc-guard` sanitizer coverage
+//! callbacks, and shared-memory testcase delivery. It does not support the AFL++ LLVM plugins
+//! (CMPLOG, IJON, etc.), which call into other parts of `afl-compiler-rt.o`.
+//!
+//! This module is itself compiled with coverage instrumentation. LLVM does not instrument
+//! functions whose names start with `__sanitizer_`, so the callbacks below must not call other
+//! (non-inlined) functions. Everything else writes its coverage to a private area except in the
+//! forked children, so the forkserver does not pollute the coverage of the runs it supervises.
+
+#![allow(non_upper_case_globals)]
+
+use std::env;
+use std::ptr;
+
+const FORKSRV_FD: i32 = 198;
+
+const SHM_ENV_VAR: &str = "__AFL_SHM_ID";
+const SHM_FUZZ_ENV_VAR: &str = "__AFL_SHM_FUZZ_ID";
+const PERSISTENT_ENV_VAR: &str = "__AFL_PERSISTENT";
+
+const FS_NEW_VERSION_MAX: u32 = 1;
+const FS_NEW_OPT_MAPSIZE: u32 = 0x0000_0001;
+const FS_NEW_OPT_SHDMEM_FUZZ: u32 = 0x0000_0002;
+const FS_NEW_OPT_AUTODICT: u32 = 0x0000_0800;
+
+/// Size of the private area that coverage is written to until the shared map is attached
+const MAP_INITIAL_SIZE: usize = 1 << 16;
+
+static mut AREA_INITIAL: [u8; MAP_INITIAL_SIZE] = [0; MAP_INITIAL_SIZE];
+
+/// Where coverage is currently written; read by [`__sanitizer_cov_trace_pc_guard`]
+#[unsafe(no_mangle)]
+pub static mut __afl_area_ptr: *mut u8 = (&raw mut AREA_INITIAL).cast();
+
+/// Private area large enough for every guard
+static mut AREA_PRIVATE: *mut u8 = (&raw mut AREA_INITIAL).cast();
+
+/// The coverage map shared with afl-fuzz, or null if there is none
+static mut AREA_SHARED: *mut u8 = ptr::null_mut();
+
+/// Number of guards, i.e., the largest index written to the coverage map
+static mut FINAL_LOC: u32 = 0;
+
+/// Size of the coverage map this binary needs, as reported to afl-fuzz: one entry per guard plus
+/// entry 0, rounded up to a multiple of 64
+#[unsafe(no_mangle)]
+pub static mut __afl_map_size: u32 = 64;
+
+static mut IS_PERSISTENT: bool = false;
+
+#[unsafe(no_mangle)]
+pub static mut __afl_fuzz_ptr: *const u8 = ptr::null();
+
+#[unsafe(no_mangle)]
+pub static mut __afl_fuzz_len: *const u32 = ptr::null();
+
+/// Tokens sent to afl-fuzz when the forkserver starts, in the autodictionary format (see
+/// `dictionary.rs`)
+#[unsafe(no_mangle)]
+pub static mut __afl_dictionary: *mut u8 = ptr::null_mut();
+
+#[unsafe(no_mangle)]
+pub static mut __afl_dictionary_len: u32 = 0;
+
+/// Number each guard, so that each edge gets its own entry in the coverage map.
+///
+/// # Safety
+///
+/// Called by the constructors that sanitizer coverage inserts, with the bounds of the
+/// `__sancov_guards` section.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __sanitizer_cov_trace_pc_guard_init(mut start: *mut u32, stop: *mut u32) {
+    unsafe {
+        // Every module's constructor passes the same (linker-merged) section.
+        if start == stop || *start != 0 {
+            return;
+        }
+        while start < stop {
+            FINAL_LOC += 1;
+            *start = FINAL_LOC;
+            start = start.add(1);
+        }
+        __afl_map_size = (FINAL_LOC + 64) & !63;
+        if __afl_map_size as usize > MAP_INITIAL_SIZE {
+            let area = libc::calloc(__afl_map_size as usize, 1).cast::<u8>();
+            if area.is_null() {
+                libc::_exit(1);
+            }
+            AREA_PRIVATE = area;
+            __afl_area_ptr = area;
+        }
+    }
+}
+
+/// Record that the edge identified by `guard` was taken.
+///
+/// # Safety
+///
+/// Called by code that sanitizer coverage instruments, with a guard numbered by
+/// [`__sanitizer_cov_trace_pc_guard_init`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __sanitizer_cov_trace_pc_guard(guard: *mut u32) {
+    unsafe {
+        let counter = __afl_area_ptr.add(*guard as usize);
+        // "NeverZero": a counter that overflows goes to 1, not 0, so the edge is not forgotten.
+        *counter = (*counter).wrapping_add(1);
+        if *counter == 0 {
+            *counter = 1;
+        }
+    }
+}
+
+// `-sanitizer-coverage-trace-compares` inserts calls to these. AFL++ uses them only for features
+// this runtime does not provide.
+macro_rules! ignore_callbacks {
+    ($($name:ident($($ty:ty),*);)*) => {
+        $(
+            #[unsafe(no_mangle)]
+            pub extern "C" fn $name($(_: $ty),*) {}
+        )*
+    };
+}
+
+ignore_callbacks! {
+    __sanitizer_cov_trace_cmp1(u8, u8);
+    __sanitizer_cov_trace_cmp2(u16, u16);
+    __sanitizer_cov_trace_cmp4(u32, u32);
+    __sanitizer_cov_trace_cmp8(u64, u64);
+    __sanitizer_cov_trace_const_cmp1(u8, u8);
+    __sanitizer_cov_trace_const_cmp2(u16, u16);
+    __sanitizer_cov_trace_const_cmp4(u32, u32);
+    __sanitizer_cov_trace_const_cmp8(u64, u64);
+    __sanitizer_cov_trace_switch(u64, *const u64);
+}
+
+/// Attach the shared memory and start the forkserver. Returns in each forked child.
+///
+/// # Safety
+///
+/// Must be called from a single thread, before any other thread is started.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __afl_manual_init() {
+    static mut INIT_DONE: bool = false;
+
+    unsafe {
+        if INIT_DONE {
+            return;
+        }
+        INIT_DONE = true;
+
+        IS_PERSISTENT = env::var_os(PERSISTENT_ENV_VAR).is_some();
+        map_shm();
+        let shm_fuzz = map_shm_fuzz();
+        start_forkserver(shm_fuzz);
+    }
+}
+
+/// Return nonzero while the persistent loop should run another iteration.
+///
+/// # Safety
+///
+/// Must be called after [`__afl_manual_init`], from the thread that called it.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __afl_persistent_loop(max_cnt: usize) -> isize {
+    static mut FIRST_PASS: bool = true;
+    static mut CYCLE_CNT: usize = 0;
+
+    unsafe {
+        if FIRST_PASS {
+            FIRST_PASS = false;
+            // Forget the coverage of everything that ran before the first iteration.
+            if IS_PERSISTENT && __afl_area_ptr == AREA_SHARED {
+                ptr::write_bytes(__afl_area_ptr, 0, map_size());
+                *__afl_area_ptr = 1;
+            }
+            CYCLE_CNT = max_cnt;
+            return 1;
+        }
+
+        if IS_PERSISTENT {
+            CYCLE_CNT -= 1;
+            if CYCLE_CNT != 0 {
+                // afl-fuzz resumes the child with SIGCONT when the next testcase is ready.
+                libc::raise(libc::SIGSTOP);
+                *__afl_area_ptr = 1;
+                return 1;
+            }
+            // Whatever runs after the loop does not belong to any testcase.
+            __afl_area_ptr = AREA_PRIVATE;
+        }
+
+        0
+    }
+}
+
+/// [`__afl_map_size`], as a `usize`
+fn map_size() -> usize {
+    unsafe { __afl_map_size as usize }
+}
+
+unsafe fn map_shm() {
+    let Some(shm_id) = shm_id(SHM_ENV_VAR) else {
+        return;
+    };
+
+    unsafe {
+        // If the map afl-fuzz created is too small, afl-fuzz restarts the target with a larger one
+        // once it learns the map size. Until then, keep writing to the private area.
+        let mut shmid_ds = std::mem::zeroed::<libc::shmid_ds>();
+        if libc::shmctl(shm_id, libc::IPC_STAT, &raw mut shmid_ds) == 0
+            && shmid_ds.shm_segsz < map_size()
+        {
+            return;
+        }
+
+        let area = libc::shmat(shm_id, ptr::null(), 0);
+        if area as isize == -1 {
+            eprintln!("afl.rs: could not attach the coverage map");
+            libc::_exit(1);
+        }
+        AREA_SHARED = area.cast();
+    }
+}
+
+/// Attach the shared memory that afl-fuzz writes testcases to, and return whether there is any
+unsafe fn map_shm_fuzz() -> bool {
+    unsafe {
+        if crate::__afl_sharedmem_fuzzing == 0 {
+            return false;
+        }
+    }
+    let Some(shm_id) = shm_id(SHM_FUZZ_ENV_VAR) else {
+        return false;
+    };
+
+    unsafe {
+        let map = libc::shmat(shm_id, ptr::null(), 0);
+        if map as isize == -1 {
+            eprintln!("afl.rs: could not attach the testcase shared memory");
+            libc::_exit(1);
+        }
+        // The testcase's length is followed by the testcase itself.
+        __afl_fuzz_len = map.cast::<u32>();
+        __afl_fuzz_ptr = map.cast::<u8>().add(size_of::<u32>());
+    }
+
+    true
+}
+
+fn shm_id(env_var: &str) -> Option<i32> {
+    env::var(env_var).ok()?.parse().ok()
+}
+
+unsafe fn start_forkserver(shm_fuzz: bool) {
+    const VERSION: u32 = 0x4146_4c00 + FS_NEW_VERSION_MAX; // "AFL\0" + version
+
+    unsafe {
+        // If no AFL++ tool is on the other end of the pipe, run without a forkserver.
+        if !write_u32(FORKSRV_FD + 1, VERSION) {
+            if !AREA_SHARED.is_null() {
+                __afl_area_ptr = AREA_SHARED;
+            }
+            return;
+        }
+
+        let reply = read_u32(FORKSRV_FD);
+        if reply != Some(VERSION ^ 0xffff_ffff) {
+            eprintln!("afl.rs: wrong forkserver message from AFL++ tool");
+            libc::_exit(1);
+        }
+
+        let dictionary = if __afl_dictionary.is_null() {
+            &[][..]
+        } else {
+            std::slice::from_raw_parts(__afl_dictionary, __afl_dictionary_len as usize)
+        };
+        let mut options = FS_NEW_OPT_MAPSIZE;
+        if shm_fuzz {
+            options |= FS_NEW_OPT_SHDMEM_FUZZ;
+        }
+        if !dictionary.is_empty() {
+            options |= FS_NEW_OPT_AUTODICT;
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        let map_size = map_size() as u32;
+        // The option values follow the options, in increasing order of option bit. Shared-memory
+        // fuzzing has no value, and the dictionary is its length followed by its contents. The
+        // welcome message ends the negotiation.
+        if !(write_u32(FORKSRV_FD + 1, options)
+            && write_u32(FORKSRV_FD + 1, map_size)
+            && (dictionary.is_empty()
+                || (write_u32(FORKSRV_FD + 1, __afl_dictionary_len)
+                    && write_all(FORKSRV_FD + 1, dictionary)))
+            && write_u32(FORKSRV_FD + 1, VERSION))
+        {
+            libc::_exit(1);
+        }
+
+        run_forkserver();
+    }
+}
+
+/// Fork a child for each run that afl-fuzz requests, and report how it ended. Returns only in the
+/// children.
+unsafe fn run_forkserver() {
+    let mut child_pid: libc::pid_t = 0;
+    let mut child_stopped = false;
+
+    unsafe {
+        loop {
+            let Some(was_killed) = read_u32(FORKSRV_FD) else {
+                libc::_exit(1);
+            };
+
+            let mut status = 0;
+
+            // If the stopped child was killed by afl-fuzz (e.g., because of a timeout), reap it.
+            if child_stopped && was_killed != 0 {
+                child_stopped = false;
+                if libc::waitpid(child_pid, &raw mut status, 0) < 0 {
+                    libc::_exit(1);
+                }
+            }
+
+            if child_stopped {
+                // A persistent-mode child is waiting for the next testcase.
+                libc::kill(child_pid, libc::SIGCONT);
+                child_stopped = false;
+            } else {
+                child_pid = libc::fork();
+                if child_pid < 0 {
+                    libc::_exit(1);
+                }
+                if child_pid == 0 {
+                    libc::close(FORKSRV_FD);
+                    libc::close(FORKSRV_FD + 1);
+                    if !AREA_SHARED.is_null() {
+                        __afl_area_ptr = AREA_SHARED;
+                    }
+                    return;
+                }
+            }
+
+            #[allow(clippy::cast_sign_loss)]
+            if !write_u32(FORKSRV_FD + 1, child_pid as u32) {
+                libc::_exit(1);
+            }
+
+            let options = if IS_PERSISTENT { libc::WUNTRACED } else { 0 };
+            if libc::waitpid(child_pid, &raw mut status, options) < 0 {
+                libc::_exit(1);
+            }
+            if libc::WIFSTOPPED(status) {
+                child_stopped = true;
+            }
+
+            #[allow(clippy::cast_sign_loss)]
+            if !write_u32(FORKSRV_FD + 1, status as u32) {
+                libc::_exit(1);
+            }
+        }
+    }
+}
+
+fn write_u32(fd: i32, value: u32) -> bool {
+    let bytes = value.to_ne_bytes();
+    unsafe { libc::write(fd, bytes.as_ptr().cast(), bytes.len()) == 4 }
+}
+
+fn write_all(fd: i32, mut bytes: &[u8]) -> bool {
+    while !bytes.is_empty() {
+        let n = unsafe { libc::write(fd, bytes.as_ptr().cast(), bytes.len()) };
+        let Ok(n) = usize::try_from(n) else {
+            return false;
+        };
+        if n == 0 {
+            return false;
+        }
+        bytes = &bytes[n..];
+    }
+    true
+}
+
+fn read_u32(fd: i32) -> Option<u32> {
+    let mut bytes = [0u8; 4];
+    let n = unsafe { libc::read(fd, bytes.as_mut_ptr().cast(), bytes.len()) };
+    (n == 4).then(|| u32::from_ne_bytes(bytes))
+}
@@ -0,0 +1,493 @@
+//! Fuzzing a stateful API with sequences of operations, optionally against a reference model
+//!
+//! Each input is decoded to a sequence of [`System::Operation`]s (with
+//! [`TakeRest`](crate::TakeRest)), which are applied in turn to a fresh system under test and, if
+//! there is one, to a fresh model. After each operation, the system's invariants are checked, and its
+//! output is compared with the model's. When an operation panics, an invariant does not hold, or
+//! the outputs differ, the fuzz target panics with the sequence.
+//!
+//! When the fuzz target runs a crashing input outside afl-fuzz (e.g., `target/debug/my_target
+//! out/default/crashes/id:...`), the sequence is first shrunk to a minimal one that still fails.
+//! Under afl-fuzz, shrinking is skipped, as it runs the sequence many times and could exceed
+//! afl-fuzz's timeout, which would turn the crash into a hang.
+//!
+//! ```rust,no_run
+//! # extern crate afl;
+//! use afl::stateful::{Harness, System};
+//! use std::collections::BTreeMap;
+//!
+//! #[derive(arbitrary::Arbitrary, Debug)]
+//! enum Operation {
+//!     Put(u8, u8),
+//!     Get(u8),
+//!     Delete(u8),
+//! }
+//!
+//! /// The system under test
+//! #[derive(Default)]
+//! struct Store(Vec<(u8, u8)>);
+//!
+//! impl System for Store {
+//!     type Operation = Operation;
+//!     type Output = Option<u8>;
+//!
+//!     fn apply(&mut self, operation: &Operation) -> Option<u8> {
+//!         let position = |key| self.0.iter().position(|&(k, _)| k == key);
+//!         match *operation {
+//!             Operation::Put(key, value) => match position(key) {
+//!                 Some(i) => Some(std::mem::replace(&mut self.0[i].1, value)),
+//!                 None => {
+//!                     self.0.push((key, value));
+//!                     None
+//!                 }
+//!             },
+//!             Operation::Get(key) => position(key).map(|i| self.0[i].1),
+//!             Operation::Delete(key) => position(key).map(|i| self.0.swap_remove(i).1),
+//!         }
+//!     }
+//!
+//!     fn check(&self) -> Result<(), String> {
+//!         if self.0.len() > 256 {
+//!             return Err(format!("{} keys", self.0.len()));
+//!         }
+//!         Ok(())
+//!     }
+//! }
+//!
+//! /// The reference model
+//! #[derive(Default)]
+//! struct Model(BTreeMap<u8, u8>);
+//!
+//! impl System for Model {
+//!     type Operation = Operation;
+//!     type Output = Option<u8>;
+//!
+//!     fn apply(&mut self, operation: &Operation) -> Option<u8> {
+//!         match *operation {
+//!             Operation::Put(key, value) => self.0.insert(key, value),
+//!             Operation::Get(key) => self.0.get(&key).copied(),
+//!             Operation::Delete(key) => self.0.remove(&key),
+//!         }
+//!     }
+//! }
+//!
+//! # fn main() -> Result<(), afl::Error> {
+//! Harness::new(Store::default).model(Model::default).run()
+//! # }
+//! ```
+//!
+//! A system is created for each input. Static state that outlives it should be reset with
+//! [`Fuzzer::reset`] or [`#[afl::on_reset]`](crate::on_reset), e.g., on a `Fuzzer` passed to
+//! [`Harness::fuzzer`]. Both are also called between the runs that shrinking makes.
+
+use crate::{Error, FuzzInput, Fuzzer, TakeRest, crash_record::panic_message};
+use arbitrary::Arbitrary;
+use std::env;
+use std::fmt::{Debug, Write as _};
+use std::panic::{self, AssertUnwindSafe};
+
+/// Set by afl-fuzz, which runs each input under a timeout that shrinking could exceed
+const SHM_ENV_VAR: &str = "__AFL_SHM_ID";
+
+/// A system under test, or a reference model of one
+pub trait System {
+    /// An operation on the system, decoded from the input
+    type Operation: for<'a> Arbitrary<'a> + Debug;
+
+    /// What an operation returns, which is compared with the model's
+    type Output: Debug;
+
+    /// Apply `operation` to the system.
+    fn apply(&mut self, operation: &Self::Operation) -> Self::Output;
+
+    /// Check the system's invariants, which is done after each operation.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description of the invariant that does not hold, if any.
+    fn check(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Applies an operation to a model and compares the model's output with the system's
+type ModelStep<'a, S> =
+    Box<dyn FnMut(&<S as System>::Operation, &<S as System>::Output) -> Result<(), String> + 'a>;
+
+/// Runs sequences of operations on a [`System`]; see the [module documentation](self)
+#[must_use = "a `Harness` does nothing until `run` is called"]
+pub struct Harness<'a, S: System> {
+    fuzzer: Fuzzer<'a>,
+    new_system: Box<dyn FnMut() -> S + 'a>,
+    new_model: Option<Box<dyn FnMut() -> ModelStep<'a, S> + 'a>>,
+}
+
+/// Why a sequence of operations failed
+struct Failure {
+    /// The index of the operation after which the failure was detected
+    step: usize,
+    reason: String,
+}
+
+impl<'a, S: System + 'a> Harness<'a, S> {
+    /// Create a harness that calls `new_system` to create the system for each input.
+    pub fn new(new_system: impl FnMut() -> S + 'a) -> Self {
+        Self {
+            fuzzer: Fuzzer::new(),
+            new_system: Box::new(new_system),
+            new_model: None,
+        }
+    }
+
+    /// Apply each operation to a model created by `new_model` too, and compare its output with
+    /// the system's.
+    pub fn model<M>(mut self, mut new_model: impl FnMut() -> M + 'a) -> Self
+    where
+        M: System<Operation = S::Operation> + 'a,
+        S::Output: PartialEq<M::Output>,
+    {
+        self.new_model = Some(Box::new(move || {
+            let mut model = new_model();
+            Box::new(move |operation, actual| {
+                let expected = model.apply(operation);
+                if *actual == expected {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "the system returned {actual:?}, but the model returned {expected:?}"
+                    ))
+                }
+            })
+        }));
+        self
+    }
+
+    /// Run with `fuzzer`'s options. Its panic hook is disabled, so that panics can be caught and
+    /// their operation sequences shrunk. Its [`reset`](Fuzzer::reset) closure is called after each
+    /// run of a sequence.
+    pub fn fuzzer(mut self, fuzzer: Fuzzer<'a>) -> Self {
+        self.fuzzer = fuzzer;
+        self
+    }
+
+    /// Fuzz the system, by passing it each sequence of operations that afl-fuzz provides.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, before any input is run, if an option of the [`Fuzzer`] or an environment
+    /// variable that overrides one has an invalid value.
+    pub fn run(self) -> Result<(), Error> {
+        let Self {
+            mut fuzzer,
+            new_system,
+            new_model,
+        } = self;
+        let mut runner = AssertUnwindSafe(Runner {
+            new_system,
+            new_model,
+            reset: fuzzer.take_reset(),
+        });
+        let shrink = env::var_os(SHM_ENV_VAR).is_none();
+        fuzzer.panic_hook(false).run(move |data| {
+            let Some(TakeRest(operations)) = TakeRest::<Vec<S::Operation>>::decode(data) else {
+                return;
+            };
+            let operations = operations.iter().collect::<Vec<_>>();
+            let result = runner.execute(&operations);
+            // The `Fuzzer` calls the registered reset hooks after this.
+            if let Some(reset) = &mut runner.reset {
+                reset();
+            }
+            let Err(failure) = result else {
+                return;
+            };
+            if shrink {
+                panic!("{}", runner.shrink(&operations, failure));
+            } else {
+                panic!(
+                    "the stateful test failed after {} operation(s), which are not shrunk under \
+                     afl-fuzz (run the fuzz target on the crashing input to shrink them)\n\n{}",
+                    failure.step + 1,
+                    report(&operations[..=failure.step], &failure)
+                );
+            }
+        })
+    }
+}
+
+struct Runner<'a, S: System> {
+    new_system: Box<dyn FnMut() -> S + 'a>,
+    new_model: Option<Box<dyn FnMut() -> ModelStep<'a, S> + 'a>>,
+    /// The `Fuzzer`'s reset closure
+    reset: Option<Box<dyn FnMut() + 'a>>,
+}
+
+impl<S: System> Runner<'_, S> {
+    /// Apply `operations` to a fresh system (and model), and stop at the first failure.
+    fn execute(&mut self, operations: &[&S::Operation]) -> Result<(), Failure> {
+        let mut system = (self.new_system)();
+        let mut model = self.new_model.as_mut().map(|new_model| new_model());
+        for (step, &operation) in operations.iter().enumerate() {
+            let fail = |reason| Failure { step, reason };
+            let output = catch(|| system.apply(operation))
+                .map_err(|message| fail(format!("the system panicked: {message}")))?;
+            if let Some(model) = &mut model {
+                catch(|| model(operation, &output))
+                    .unwrap_or_else(|message| Err(format!("the model panicked: {message}")))
+                    .map_err(fail)?;
+            }
+            catch(|| system.check())
+                .unwrap_or_else(|message| Err(format!("panicked: {message}")))
+                .map_err(|reason| fail(format!("an invariant does not hold: {reason}")))?;
+        }
+        Ok(())
+    }
+
+    /// Call the `Fuzzer`'s reset closure and the registered reset hooks.
+    fn reset(&mut self) {
+        if let Some(reset) = &mut self.reset {
+            reset();
+        }
+        crate::reset::run();
+    }
+
+    /// Shrink the failing `operations` to a sequence from which no operation can be removed, and
+    /// describe how it fails. Static state is reset after each run, as it is after the one that
+    /// found the failure.
+    fn shrink(&mut self, operations: &[&S::Operation], failure: Failure) -> String {
+        // Panics are expected while shrinking, so keep them from being printed.
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(|_| {}));
+        // The failing run was followed by the reset closure, but not yet by the hooks.
+        crate::reset::run();
+        let original_len = failure.step + 1;
+        let mut trace = operations[..original_len].to_vec();
+        let mut failure = failure;
+        let mut chunk = trace.len().div_ceil(2);
+        loop {
+            let mut start = 0;
+            while start < trace.len() {
+                let mut candidate = trace[..start].to_vec();
+                candidate.extend_from_slice(&trace[(start + chunk).min(trace.len())..]);
+                let result = self.execute(&candidate);
+                self.reset();
+                if let Err(candidate_failure) = result {
+                    candidate.truncate(candidate_failure.step + 1);
+                    trace = candidate;
+                    failure = candidate_failure;
+                } else {
+                    start += chunk;
+                }
+            }
+            if chunk == 1 {
+                break;
+            }
+            chunk = chunk.div_ceil(2);
+        }
+        panic::set_hook(hook);
+        format!(
+            "the stateful test failed after {} operation(s) (shrunk from {original_len})\n\n{}",
+            trace.len(),
+            report(&trace, &failure)
+        )
+    }
+}
+
+/// List the operations of `trace`, and describe how it fails.
+fn report<O: Debug>(trace: &[&O], failure: &Failure) -> String {
+    let mut report = String::from("operations:\n");
+    for (step, operation) in trace.iter().enumerate() {
+        let _ = writeln!(report, "    {step}: {operation:?}");
+    }
+    let _ = write!(
+        report,
+        "\nfailure at operation {}: {}",
+        failure.step, failure.reason
+    );
+    report
+}
+
+/// Run `f`, and return the message it panics with, if any.
+fn catch<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| panic_message(&*payload).to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[derive(arbitrary::Arbitrary, Clone, Copy, Debug)]
+    enum Operation {
+        Increment,
+        Decrement,
+        Nothing,
+    }
+
+    use Operation::{Decrement, Increment, Nothing};
+
+    /// A counter that saturates at 0, and must not exceed 2
+    #[derive(Default)]
+    struct Counter(i32);
+
+    impl System for Counter {
+        type Operation = Operation;
+        type Output = i32;
+
+        fn apply(&mut self, operation: &Operation) -> i32 {
+            match operation {
+                Increment => self.0 += 1,
+                Decrement => self.0 = (self.0 - 1).max(0),
+                Nothing => {}
+            }
+            self.0
+        }
+
+        fn check(&self) -> Result<(), String> {
+            if self.0 > 2 {
+                return Err(format!("{} > 2", self.0));
+            }
+            Ok(())
+        }
+    }
+
+    /// A counter that goes below 0, unlike `Counter`
+    #[derive(Default)]
+    struct Model(i32);
+
+    impl System for Model {
+        type Operation = Operation;
+        type Output = i32;
+
+        fn apply(&mut self, operation: &Operation) -> i32 {
+            match operation {
+                Increment => self.0 += 1,
+                Decrement => self.0 -= 1,
+                Nothing => {}
+            }
+            self.0
+        }
+    }
+
+    fn runner<S: System>(harness: Harness<'_, S>) -> Runner<'_, S> {
+        Runner {
+            new_system: harness.new_system,
+            new_model: harness.new_model,
+            reset: None,
+        }
+    }
+
+    fn shrink<S: System>(runner: &mut Runner<'_, S>, operations: &[S::Operation]) -> String {
+        let operations = operations.iter().collect::<Vec<_>>();
+        let failure = runner
+            .execute(&operations)
+            .expect_err("the operations do not fail");
+        // As in `fuzz`, the failing run is followed by the reset closure.
+        if let Some(reset) = &mut runner.reset {
+            reset();
+        }
+        runner.shrink(&operations, failure)
+    }
+
+    #[test]
+    fn passing_sequences_pass() {
+        let mut runner = runner(Harness::new(Counter::default).model(Model::default));
+        assert!(
+            runner
+                .execute(&[&Increment, &Decrement, &Increment])
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn invariant_failures_are_shrunk() {
+        let mut runner = runner(Harness::new(Counter::default));
+        let operations = [
+            Nothing, Increment, Decrement, Increment, Nothing, Increment, Increment, Nothing,
+        ];
+        assert_eq!(
+            shrink(&mut runner, &operations),
+            "the stateful test failed after 3 operation(s) (shrunk from 7)\n\n\
+             operations:\n    0: Increment\n    1: Increment\n    2: Increment\n\n\
+             failure at operation 2: an invariant does not hold: 3 > 2"
+        );
+    }
+
+    #[test]
+    fn model_mismatches_are_shrunk() {
+        let mut runner = runner(Harness::new(Counter::default).model(Model::default));
+        let operations = [Increment, Nothing, Decrement, Decrement];
+        assert_eq!(
+            shrink(&mut runner, &operations),
+            "the stateful test failed after 1 operation(s) (shrunk from 4)\n\n\
+             operations:\n    0: Decrement\n\n\
+             failure at operation 0: the system returned 0, but the model returned -1"
+        );
+    }
+
+    #[test]
+    fn panics_are_shrunk() {
+        struct Panicking;
+
+        impl System for Panicking {
+            type Operation = Operation;
+            type Output = ();
+
+            fn apply(&mut self, operation: &Operation) {
+                assert!(!matches!(operation, Decrement), "decremented");
+            }
+        }
+
+        let mut runner = runner(Harness::new(|| Panicking));
+        let report = shrink(&mut runner, &[Increment, Nothing, Decrement]);
+        assert!(
+            report.starts_with("the stateful test failed after 1 operation(s) (shrunk from 3)"),
+            "{report}"
+        );
+        assert!(
+            report.ends_with("failure at operation 0: the system panicked: decremented"),
+            "{report}"
+        );
+    }
+
+    thread_local! {
+        static TOTAL: Cell<u32> = const { Cell::new(0) };
+    }
+
+    /// Fails once three increments have been made since `TOTAL` was last reset
+    struct Leaky;
+
+    impl System for Leaky {
+        type Operation = Operation;
+        type Output = ();
+
+        fn apply(&mut self, operation: &Operation) {
+            if let Increment = operation {
+                TOTAL.set(TOTAL.get() + 1);
+            }
+        }
+
+        fn check(&self) -> Result<(), String> {
+            if TOTAL.get() > 2 {
+                return Err(String::from("too many increments"));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn static_state_is_reset_between_runs() {
+        TOTAL.set(0);
+        let mut runner = runner(Harness::new(|| Leaky));
+        runner.reset = Some(Box::new(|| TOTAL.set(0)));
+        let report = shrink(
+            &mut runner,
+            &[Nothing, Increment, Nothing, Increment, Increment],
+        );
+        // Without the reset, leftover increments would let shorter sequences fail.
+        assert!(
+            report.starts_with("the stateful test failed after 3 operation(s) (shrunk from 5)"),
+            "{report}"
+        );
+    }
+}
@@ -0,0 +1,50 @@
+//! Runtime selection of a fuzz target, for binaries built with [`targets!`](crate::targets)
+
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const TARGET_ENV_VAR: &str = "AFL_RS_TARGET";
+
+/// Whether the target was named by the first command-line argument, which then is not an input
+static TARGET_IN_ARGS: AtomicBool = AtomicBool::new(false);
+
+/// Return the name of the target to run: the value of `AFL_RS_TARGET` if it is set, otherwise the
+/// first command-line argument. Exit if it is not one of `names`.
+///
+/// This must be called before the forkserver starts, so that the processes afl-fuzz forks run only
+/// the selected target.
+#[doc(hidden)]
+#[must_use]
+pub fn __select(names: &[&'static str]) -> &'static str {
+    let (name, in_args) = match env::var_os(TARGET_ENV_VAR) {
+        Some(name) => (Some(name), false),
+        None => (env::args_os().nth(1), true),
+    };
+    let Some(name) = name else {
+        exit_with_targets(
+            &format!("no target given; pass one as the first argument or set `{TARGET_ENV_VAR}`"),
+            names,
+        );
+    };
+    let Some(&selected) = names.iter().find(|&&candidate| name == candidate) else {
+        exit_with_targets(
+            &format!("unknown target `{}`", name.to_string_lossy()),
+            names,
+        );
+    };
+    TARGET_IN_ARGS.store(in_args, Ordering::Relaxed);
+    selected
+}
+
+/// The number of leading command-line arguments (after the program name) that are not inputs
+pub(crate) fn args_consumed() -> usize {
+    usize::from(TARGET_IN_ARGS.load(Ordering::Relaxed))
+}
+
+fn exit_with_targets(message: &str, names: &[&str]) -> ! {
+    eprintln!("afl: {message}. The targets are:");
+    for name in names {
+        eprintln!("    {name}");
+    }
+    std::process::exit(1);
+}
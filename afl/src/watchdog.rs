@@ -0,0 +1,135 @@
+//! A per-iteration timeout that aborts with a backtrace, so that a hang can be diagnosed by
+//! replaying its input
+
+use std::time::Duration;
+
+/// Set by `cargo afl fuzz` to the value of afl-fuzz's `-t`, in milliseconds
+const EXEC_TIMEOUT_ENV_VAR: &str = "AFL_RS_EXEC_TIMEOUT";
+
+/// Just under afl-fuzz's timeout, so that the watchdog fires before afl-fuzz kills the process, or
+/// `None` if `-t` was not given. afl-fuzz then calibrates its timeout on the seed inputs without
+/// telling the fuzz target, so no timeout would be right for every target.
+pub(crate) fn default_timeout() -> Option<Duration> {
+    timeout_for(std::env::var(EXEC_TIMEOUT_ENV_VAR).ok().as_deref())
+}
+
+fn timeout_for(exec_timeout: Option<&str>) -> Option<Duration> {
+    let exec_timeout = Duration::from_millis(exec_timeout?.parse().ok()?);
+    Some(exec_timeout * 9 / 10)
+}
+
+#[cfg(unix)]
+pub(crate) use imp::Watchdog;
+
+#[cfg(unix)]
+mod imp {
+    use std::backtrace::Backtrace;
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    static TIMEOUT_MS: AtomicU64 = AtomicU64::new(0);
+    static INPUT_LEN: AtomicUsize = AtomicUsize::new(0);
+    /// The `pthread_t` of the thread that runs the fuzz target
+    static FUZZING_THREAD: AtomicUsize = AtomicUsize::new(0);
+
+    /// Delivers `SIGALRM` when an iteration runs longer than the timeout
+    pub(crate) struct Watchdog {
+        timeout: Duration,
+    }
+
+    impl Watchdog {
+        /// Install the `SIGALRM` handler. The calling thread is the one whose backtrace is printed.
+        pub(crate) fn install(timeout: Duration) -> Self {
+            TIMEOUT_MS.store(
+                u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX),
+                Ordering::Relaxed,
+            );
+            FUZZING_THREAD.store(current_thread(), Ordering::Relaxed);
+            unsafe {
+                let mut action = std::mem::zeroed::<libc::sigaction>();
+                action.sa_sigaction = on_timeout as extern "C" fn(libc::c_int) as usize;
+                libc::sigemptyset(&raw mut action.sa_mask);
+                libc::sigaction(libc::SIGALRM, &raw const action, std::ptr::null_mut());
+            }
+            Self { timeout }
+        }
+
+        /// Start timing an iteration on an input of `input_len` bytes.
+        pub(crate) fn arm(&self, input_len: usize) {
+            INPUT_LEN.store(input_len, Ordering::Relaxed);
+            set_timer(self.timeout);
+        }
+
+        /// Stop timing the current iteration.
+        #[allow(clippy::unused_self)]
+        pub(crate) fn disarm(&self) {
+            set_timer(Duration::ZERO);
+        }
+    }
+
+    fn set_timer(duration: Duration) {
+        let timer = libc::itimerval {
+            it_interval: libc::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            it_value: libc::timeval {
+                tv_sec: libc::time_t::try_from(duration.as_secs()).unwrap_or(libc::time_t::MAX),
+                tv_usec: libc::suseconds_t::from(duration.subsec_micros().cast_signed()),
+            },
+        };
+        unsafe { libc::setitimer(libc::ITIMER_REAL, &raw const timer, std::ptr::null_mut()) };
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn current_thread() -> usize {
+        unsafe { libc::pthread_self() as usize }
+    }
+
+    extern "C" fn on_timeout(_: libc::c_int) {
+        // The signal goes to the process, so any thread may receive it.
+        let fuzzing_thread = FUZZING_THREAD.load(Ordering::Relaxed);
+        if current_thread() != fuzzing_thread {
+            unsafe { libc::pthread_kill(fuzzing_thread as libc::pthread_t, libc::SIGALRM) };
+            return;
+        }
+        // Capturing a backtrace in a signal handler is not async-signal-safe, but the process is
+        // about to abort anyway. Write to the file descriptor directly, in case the interrupted
+        // code holds the lock on `stderr`.
+        let message = format!(
+            "afl: an iteration timed out after {} ms, on an input of {} bytes\n{}\n",
+            TIMEOUT_MS.load(Ordering::Relaxed),
+            INPUT_LEN.load(Ordering::Relaxed),
+            Backtrace::force_capture()
+        );
+        unsafe { libc::write(libc::STDERR_FILENO, message.as_ptr().cast(), message.len()) };
+        std::process::abort();
+    }
+}
+
+/// Does nothing; the watchdog relies on `SIGALRM`
+#[cfg(not(unix))]
+pub(crate) struct Watchdog;
+
+#[cfg(not(unix))]
+impl Watchdog {
+    pub(crate) fn install(_timeout: Duration) -> Self {
+        Self
+    }
+
+    pub(crate) fn arm(&self, _input_len: usize) {}
+
+    pub(crate) fn disarm(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeout_is_just_under_afl_fuzz() {
+        assert_eq!(timeout_for(Some("1000")), Some(Duration::from_millis(900)));
+        assert_eq!(timeout_for(None), None);
+        assert_eq!(timeout_for(Some("1000+")), None);
+    }
+}
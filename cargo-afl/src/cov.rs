@@ -0,0 +1,296 @@
+//! `cargo afl cov`, which reports the source-level coverage of a corpus
+//!
+//! The fuzz target is rebuilt with `-C instrument-coverage` (and without AFL++'s instrumentation)
+//! in its own target directory. Every input is replayed through it, the `.profraw` files the runs
+//! leave are merged with `llvm-profdata`, and `llvm-cov` writes an lcov file and an HTML report.
+
+use crate::stability::{self, run_tool};
+use crate::{manifest, options, target_dir};
+use anyhow::{Context, Result, bail, ensure};
+use cargo_metadata::{Message, MetadataCommand};
+use clap::Parser;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// How many inputs each replay run is given. A run that fails (e.g., because an input panics)
+/// writes no profile, so its inputs are then replayed one at a time.
+const INPUTS_PER_RUN: usize = 256;
+
+/// The coverage build's target directory, and the directory that holds a report for each target,
+/// both in `<target-dir>/afl/cov`. They are siblings, so that a target's name cannot clash with
+/// the build directory.
+const BUILD_DIR_NAME: &str = "build";
+const REPORT_DIR_NAME: &str = "report";
+
+#[derive(Parser)]
+#[clap(after_help = "\
+`llvm-profdata` and `llvm-cov` must match rustc's LLVM version. Install them with `rustup component \
+add llvm-tools`, or set `LLVM_PROFDATA` and `LLVM_COV`.
+
+Example:
+
+    cargo afl cov -i out/default/queue --only my_parser my_fuzz_target")]
+pub struct Args {
+    #[clap(
+        short = 'i',
+        value_name = "DIR",
+        required = true,
+        help = "Directory of inputs to replay (e.g., a queue); may be given more than once"
+    )]
+    input: Vec<PathBuf>,
+
+    #[clap(
+        long,
+        value_name = "CRATE",
+        help = "Report only on the sources of CRATE; may be given more than once"
+    )]
+    only: Vec<String>,
+
+    #[clap(
+        long,
+        value_name = "DIR",
+        help = "Where to write `lcov.info` and `html/` [default: <target-dir>/afl/cov/report/<TARGET>]"
+    )]
+    output_dir: Option<PathBuf>,
+
+    #[clap(value_name = "TARGET", help = "The binary or example to measure")]
+    target: String,
+
+    #[clap(
+        last = true,
+        value_name = "CARGO ARGS",
+        help = "Arguments for `cargo build` (e.g., `--features`)"
+    )]
+    cargo: Vec<OsString>,
+}
+
+pub fn run(args: &Args) -> Result<()> {
+    let llvm_profdata = llvm_tool("llvm-profdata", "LLVM_PROFDATA")?;
+    let llvm_cov = llvm_tool("llvm-cov", "LLVM_COV")?;
+
+    let mut inputs = Vec::new();
+    for dir in &args.input {
+        inputs.extend(stability::inputs(dir)?);
+    }
+    ensure!(
+        !inputs.is_empty(),
+        "the input directories contain no inputs"
+    );
+
+    let manifest = manifest::Manifest::load(&args.cargo);
+    let Some((package, kind_flag)) = manifest.binary_target(&args.target) else {
+        bail!(
+            "no binary or example named `{}` in the workspace",
+            args.target
+        );
+    };
+    let Some(cov_dir) = target_dir::for_coverage(&manifest) else {
+        bail!("could not determine Cargo's target directory");
+    };
+    let source_dirs = source_dirs(&args.cargo, &args.only)?;
+
+    let binary = build(&args.target, &package, kind_flag, &cov_dir, &args.cargo)?;
+
+    let temp_dir = tempfile::tempdir()?;
+    eprintln!("Replaying {} inputs...", inputs.len());
+    let failed = replay(&binary, &inputs, temp_dir.path())?;
+    if failed != 0 {
+        eprintln!("Warning: {failed} input(s) crashed or failed; their coverage is not included");
+    }
+
+    let profiles = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    ensure!(
+        !profiles.is_empty(),
+        "no run of `{}` wrote a profile",
+        binary.display()
+    );
+    let profdata = temp_dir.path().join("merged.profdata");
+    run_tool(
+        Command::new(&llvm_profdata)
+            .args(["merge", "-sparse", "-o"])
+            .arg(&profdata)
+            .args(&profiles),
+    )?;
+
+    let output_dir = args
+        .output_dir
+        .clone()
+        .unwrap_or_else(|| cov_dir.join(REPORT_DIR_NAME).join(&args.target));
+    fs::create_dir_all(&output_dir)?;
+    let llvm_cov_command = |subcommand: &str| {
+        let mut command = Command::new(&llvm_cov);
+        command
+            .arg(subcommand)
+            .arg("-instr-profile")
+            .arg(&profdata)
+            .arg(&binary)
+            .args(&source_dirs);
+        command
+    };
+    let lcov = run_tool(llvm_cov_command("export").arg("-format=lcov"))?;
+    fs::write(output_dir.join("lcov.info"), lcov)?;
+    run_tool(
+        llvm_cov_command("show")
+            .arg("-format=html")
+            .arg("-output-dir")
+            .arg(output_dir.join("html")),
+    )?;
+    let summary = run_tool(&mut llvm_cov_command("report"))?;
+    if let Some(total) = summary.lines().find(|line| line.starts_with("TOTAL")) {
+        println!("{}", summary.lines().next().unwrap_or_default());
+        println!("{total}");
+    }
+
+    eprintln!(
+        "Wrote `{}` and `{}`",
+        output_dir.join("lcov.info").display(),
+        output_dir.join("html").join("index.html").display()
+    );
+    Ok(())
+}
+
+/// Build `target` with coverage instrumentation, and return the path of the binary.
+fn build(
+    target: &str,
+    package: &str,
+    kind_flag: &str,
+    cov_dir: &Path,
+    build_args: &[OsString],
+) -> Result<PathBuf> {
+    let cargo_path = env::var_os("CARGO").unwrap_or_else(|| OsString::from("cargo"));
+    let mut args = vec![
+        OsString::from("build"),
+        OsString::from(kind_flag),
+        OsString::from(target),
+        OsString::from("--package"),
+        OsString::from(package),
+        OsString::from("--message-format=json-render-diagnostics"),
+        OsString::from("--target-dir"),
+        cov_dir.join(BUILD_DIR_NAME).into_os_string(),
+    ];
+    args.extend(build_args.iter().cloned());
+    // As in instrumented builds, keep `RUSTFLAGS` away from build scripts and proc macros.
+    let host = rustc_version::version_meta()?.host;
+    options::add_target(&mut args, &host);
+
+    let mut rustflags = String::from(
+        "-C instrument-coverage \
+         -C debug-assertions \
+         -C overflow_checks \
+         --cfg fuzzing ",
+    );
+    rustflags.push_str(&env::var("RUSTFLAGS").unwrap_or_default());
+
+    let mut child = Command::new(cargo_path)
+        .args(args)
+        .env("RUSTFLAGS", rustflags)
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("could not run `cargo build`")?;
+    let mut executable = None;
+    for message in Message::parse_stream(BufReader::new(child.stdout.take().unwrap())) {
+        if let Message::CompilerArtifact(artifact) = message?
+            && artifact.target.name == target
+            && let Some(path) = artifact.executable
+        {
+            executable = Some(path.into_std_path_buf());
+        }
+    }
+    ensure!(child.wait()?.success(), "`cargo build` failed");
+    executable.with_context(|| format!("`cargo build` did not build `{target}`"))
+}
+
+/// Run `binary` on each of `inputs`, writing profiles to `profile_dir`, and return how many
+/// inputs failed
+fn replay(binary: &Path, inputs: &[PathBuf], profile_dir: &Path) -> Result<usize> {
+    let run = |inputs: &[PathBuf]| -> Result<bool> {
+        Ok(Command::new(binary)
+            .args(inputs)
+            .env("LLVM_PROFILE_FILE", profile_dir.join("%p-%m.profraw"))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .with_context(|| format!("could not run `{}`", binary.display()))?
+            .success())
+    };
+    let mut failed = 0;
+    for chunk in inputs.chunks(INPUTS_PER_RUN) {
+        if run(chunk)? {
+            continue;
+        }
+        if chunk.len() == 1 {
+            failed += 1;
+            continue;
+        }
+        for input in chunk {
+            if !run(std::slice::from_ref(input))? {
+                failed += 1;
+            }
+        }
+    }
+    Ok(failed)
+}
+
+/// The source directories of `crates`, which can be the workspace's packages or their
+/// dependencies
+fn source_dirs(build_args: &[OsString], crates: &[String]) -> Result<Vec<PathBuf>> {
+    if crates.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut command = MetadataCommand::new();
+    if let Some(manifest_path) = manifest::cargo_option(build_args, "--manifest-path", None) {
+        command.manifest_path(manifest_path);
+    }
+    let metadata = command.exec()?;
+    crates
+        .iter()
+        .map(|name| {
+            let normalized = name.replace('-', "_");
+            metadata
+                .packages
+                .iter()
+                .find(|package| package.name.replace('-', "_") == normalized)
+                .and_then(|package| package.manifest_path.parent())
+                .map(|dir| dir.as_std_path().to_path_buf())
+                .with_context(|| format!("`{name}` is not a package in the dependency graph"))
+        })
+        .collect()
+}
+
+/// The path of `name`, from the environment variable `env_var`, rustup's `llvm-tools` component,
+/// or `PATH`, in that order
+fn llvm_tool(name: &str, env_var: &str) -> Result<PathBuf> {
+    if let Some(path) = env::var_os(env_var) {
+        return Ok(PathBuf::from(path));
+    }
+    let output = Command::new("rustc")
+        .args(["--print", "sysroot"])
+        .output()?;
+    let sysroot = String::from_utf8_lossy(&output.stdout);
+    let host = rustc_version::version_meta()?.host;
+    let path = Path::new(sysroot.trim())
+        .join("lib/rustlib")
+        .join(host)
+        .join("bin")
+        .join(name);
+    if path.exists() {
+        return Ok(path);
+    }
+    if Command::new(name)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok()
+    {
+        return Ok(PathBuf::from(name));
+    }
+    bail!("could not find `{name}`; install it with `rustup component add llvm-tools`")
+}
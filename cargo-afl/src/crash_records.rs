@@ -0,0 +1,134 @@
+//! Crash records, which the `afl` crate writes when a fuzz target panics
+//!
+//! The records are keyed by the hash of the input (see `afl/src/crash_record.rs`). Once afl-fuzz
+//! exits, each one is copied to a `crash_records` directory next to the `crashes` directory, as
+//! `<crash file>.txt`. They are not put in `crashes` itself, where afl-fuzz's tools and globs like
+//! `crashes/id:*` would take them for crashing inputs.
+
+use crate::hash::fnv1a;
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const RECORDS_DIR_ENV_VAR: &str = "AFL_RS_CRASH_RECORDS_DIR";
+
+/// Where records go when `AFL_RS_CRASH_RECORDS_DIR` is not set, relative to afl-fuzz's output
+/// directory. afl-fuzz ignores directories there that are not fuzzer instances.
+const RECORDS_DIR_NAME: &str = "afl-rs-crash-records";
+
+/// The directory, next to each fuzzer instance's `crashes` directory, that records are copied to
+pub const COPIED_RECORDS_DIR_NAME: &str = "crash_records";
+
+/// The directory fuzz targets should write records to: the user's choice, or one in afl-fuzz's
+/// output directory
+pub fn records_dir(output_dir: &Path) -> PathBuf {
+    env::var_os(RECORDS_DIR_ENV_VAR)
+        .map_or_else(|| output_dir.join(RECORDS_DIR_NAME), PathBuf::from)
+}
+
+/// Copy each record in `records_dir` to the instance's `crash_records` directory in `output_dir`,
+/// named after the crash file with the same hash, and return how many were copied. Records of crash
+/// files that no longer exist (e.g., from an earlier run) are removed.
+pub fn copy(output_dir: &Path, records_dir: &Path) -> Result<usize> {
+    let mut records = HashMap::new();
+    for entry in fs::read_dir(records_dir)? {
+        let path = entry?.path();
+        if let Some(hash) = path
+            .file_stem()
+            .and_then(|stem| u64::from_str_radix(&stem.to_string_lossy(), 16).ok())
+        {
+            records.insert(hash, path);
+        }
+    }
+
+    let mut copied = 0;
+    // Each fuzzer instance (e.g., `default`, or the name passed to `-M` or `-S`) has its own
+    // `crashes` directory.
+    for instance in fs::read_dir(output_dir)? {
+        let instance = instance?.path();
+        let Ok(crashes) = fs::read_dir(instance.join("crashes")) else {
+            continue;
+        };
+        let copied_records_dir = instance.join(COPIED_RECORDS_DIR_NAME);
+        let mut crash_names = HashSet::new();
+        for crash in crashes {
+            let crash = crash?.path();
+            let Some(name) = crash
+                .file_name()
+                .filter(|name| name.to_string_lossy().starts_with("id:"))
+            else {
+                continue;
+            };
+            crash_names.insert(name.to_owned());
+            let Some(record) = records.get(&fnv1a(&fs::read(&crash)?)) else {
+                continue;
+            };
+            let destination = copied_records_dir.join(record_name(name));
+            let contents = fs::read(record)?;
+            if fs::read(&destination).is_ok_and(|existing| existing == contents) {
+                continue;
+            }
+            fs::create_dir_all(&copied_records_dir)?;
+            fs::write(destination, contents)?;
+            copied += 1;
+        }
+
+        for entry in fs::read_dir(&copied_records_dir).into_iter().flatten() {
+            let path = entry?.path();
+            if !crash_names
+                .iter()
+                .any(|name| path.ends_with(record_name(name)))
+            {
+                fs::remove_file(path)?;
+            }
+        }
+    }
+    Ok(copied)
+}
+
+/// The name of the record of the crash file named `crash_name`
+fn record_name(crash_name: &OsStr) -> OsString {
+    let mut name = crash_name.to_owned();
+    name.push(".txt");
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_are_copied_next_to_crashes() {
+        let output_dir = tempfile::tempdir().unwrap();
+        let records_dir = output_dir.path().join(RECORDS_DIR_NAME);
+        let instance = output_dir.path().join("default");
+        let crashes = instance.join("crashes");
+        let copied_records = instance.join(COPIED_RECORDS_DIR_NAME);
+        fs::create_dir_all(&crashes).unwrap();
+        fs::create_dir_all(&records_dir).unwrap();
+        fs::write(crashes.join("README.txt"), "").unwrap();
+        fs::write(crashes.join("id:000000,sig:06"), "a").unwrap();
+        fs::write(crashes.join("id:000001,sig:06"), "b").unwrap();
+        fs::write(records_dir.join("af63dc4c8601ec8c.txt"), "panicked").unwrap();
+
+        assert_eq!(copy(output_dir.path(), &records_dir).unwrap(), 1);
+        assert_eq!(
+            fs::read_to_string(copied_records.join("id:000000,sig:06.txt")).unwrap(),
+            "panicked"
+        );
+        assert!(!copied_records.join("id:000001,sig:06.txt").exists());
+        // Nothing is added to `crashes`.
+        assert_eq!(fs::read_dir(&crashes).unwrap().count(), 3);
+
+        // Copying again does nothing.
+        assert_eq!(copy(output_dir.path(), &records_dir).unwrap(), 0);
+
+        // A record whose crash file is gone is removed.
+        fs::remove_file(crashes.join("id:000000,sig:06")).unwrap();
+        assert_eq!(copy(output_dir.path(), &records_dir).unwrap(), 0);
+        assert!(!copied_records.join("id:000000,sig:06.txt").exists());
+    }
+}
@@ -0,0 +1,223 @@
+//! Dictionaries of the constants that AFL++'s `afl-llvm-dict2file` plugin finds in each fuzz target
+//!
+//! The plugin appends every string and integer constant compared against in a module to the file
+//! named by `AFL_LLVM_DICT2FILE`. In plugin builds, the `RUSTC_WRAPPER` (see `wrapper.rs`) points it
+//! at `<crate><extra-filename>.dict` in the crate's output directory, after writing a `#
+//! dependency:` comment there for each crate the crate depends on. When a binary is linked, the
+//! tokens of all the crates it is built from are merged, deduplicated, and written next to the
+//! binary as `<binary>.dict`. `cargo afl fuzz` passes that file to afl-fuzz with `-x`.
+
+use anyhow::Result;
+use std::collections::{BTreeSet, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const DEPENDENCY_PREFIX: &str = "# dependency: ";
+
+/// Start the dictionary of the crate that `rustc_args` compile, and return its path, to which the
+/// plugin appends
+pub fn prepare(rustc_args: &[OsString]) -> Option<PathBuf> {
+    let crate_name = option_value(rustc_args, "--crate-name")?;
+    let out_dir = PathBuf::from(option_value(rustc_args, "--out-dir")?);
+    let extra_filename = codegen_option(rustc_args, "extra-filename").unwrap_or_default();
+    let path = out_dir.join(format!("{crate_name}{extra_filename}.dict"));
+
+    let mut header = String::new();
+    for dependency in dependencies(rustc_args) {
+        header.push_str(DEPENDENCY_PREFIX);
+        header.push_str(&dependency.to_string_lossy());
+        header.push('\n');
+    }
+    fs::create_dir_all(&out_dir).ok()?;
+    fs::write(&path, header).ok()?;
+    Some(path)
+}
+
+/// If `rustc_args` compiled a binary, write the merged dictionary of it and its dependencies
+/// (`dictionary`, as returned by [`prepare`]) to where Cargo puts the binary.
+pub fn finish(rustc_args: &[OsString], dictionary: &Path) -> Result<()> {
+    if option_value(rustc_args, "--crate-type").as_deref() != Some("bin") {
+        return Ok(());
+    }
+    let (Some(crate_name), Some(out_dir)) = (
+        option_value(rustc_args, "--crate-name"),
+        option_value(rustc_args, "--out-dir"),
+    ) else {
+        return Ok(());
+    };
+    let tokens = merge(dictionary);
+    if tokens.is_empty() {
+        return Ok(());
+    }
+    let mut contents = tokens.into_iter().collect::<Vec<_>>().join("\n");
+    contents.push('\n');
+    fs::write(
+        binary_dir(Path::new(&out_dir)).join(format!("{crate_name}.dict")),
+        contents,
+    )?;
+    Ok(())
+}
+
+/// The dictionary written for the fuzz target at `path`, if any. Cargo names binaries after their
+/// targets, in which hyphens become underscores in the crate name.
+pub fn for_fuzz_target(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_string_lossy().replace('-', "_");
+    let dictionary = path.with_file_name(format!("{name}.dict"));
+    dictionary.is_file().then_some(dictionary)
+}
+
+/// The tokens in `dictionary` and in the dictionaries it depends on, transitively
+fn merge(dictionary: &Path) -> BTreeSet<String> {
+    let mut tokens = BTreeSet::new();
+    let mut visited = HashSet::new();
+    let mut stack = vec![dictionary.to_path_buf()];
+    while let Some(path) = stack.pop() {
+        if !visited.insert(path.clone()) {
+            continue;
+        }
+        // Crates that were not instrumented (e.g., proc macros) have no dictionary.
+        let Ok(contents) = fs::read_to_string(&path) else {
+            continue;
+        };
+        for line in contents.lines() {
+            if let Some(dependency) = line.strip_prefix(DEPENDENCY_PREFIX) {
+                stack.push(PathBuf::from(dependency));
+            } else if line.len() > 2 && line.starts_with('"') && line.ends_with('"') {
+                tokens.insert(line.to_owned());
+            }
+        }
+    }
+    tokens
+}
+
+/// The dictionaries of the crates passed with `--extern`, which are next to their `.rlib` or
+/// `.rmeta` files
+fn dependencies(rustc_args: &[OsString]) -> Vec<PathBuf> {
+    rustc_args
+        .windows(2)
+        .filter(|pair| pair[0] == "--extern")
+        .filter_map(|pair| {
+            let (_, path) = pair[1].to_str()?.split_once('=')?;
+            let path = Path::new(path);
+            if !path
+                .extension()
+                .is_some_and(|extension| extension == "rlib" || extension == "rmeta")
+            {
+                return None;
+            }
+            let stem = path.file_stem()?.to_string_lossy();
+            let stem = stem.strip_prefix("lib").unwrap_or(&stem);
+            Some(path.with_file_name(format!("{stem}.dict")))
+        })
+        .collect()
+}
+
+/// Where Cargo copies a binary that rustc wrote to `out_dir`: binaries are built in `deps` and
+/// copied to its parent, and examples stay in `examples`
+fn binary_dir(out_dir: &Path) -> &Path {
+    if out_dir.file_name().is_some_and(|name| name == "deps") {
+        out_dir.parent().unwrap_or(out_dir)
+    } else {
+        out_dir
+    }
+}
+
+fn option_value(rustc_args: &[OsString], option: &str) -> Option<String> {
+    let index = rustc_args.iter().position(|arg| arg == option)?;
+    rustc_args
+        .get(index + 1)
+        .map(|arg| arg.to_string_lossy().into_owned())
+}
+
+/// The value of `-C <name>=<value>`
+fn codegen_option(rustc_args: &[OsString], name: &str) -> Option<String> {
+    let prefix = format!("{name}=");
+    rustc_args.iter().enumerate().find_map(|(index, arg)| {
+        let arg = arg.to_str()?;
+        let option = if arg == "-C" {
+            rustc_args.get(index + 1)?.to_str()?
+        } else {
+            arg.strip_prefix("-C")?
+        };
+        option.strip_prefix(&prefix).map(ToOwned::to_owned)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn dictionaries_are_merged() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let deps = temp_dir.path().join("debug").join("deps");
+        let deps_str = deps.to_str().unwrap();
+
+        let parser = prepare(&args(&[
+            "--crate-name",
+            "parser",
+            "--crate-type",
+            "lib",
+            "--out-dir",
+            deps_str,
+            "-C",
+            "extra-filename=-0123",
+        ]))
+        .unwrap();
+        assert_eq!(parser, deps.join("parser-0123.dict"));
+        fs::write(&parser, "\"GET\"\n\"\\x00\\x01\"\n").unwrap();
+
+        let fuzz_args = args(&[
+            "--crate-name",
+            "fuzz_parser",
+            "--crate-type",
+            "bin",
+            "--out-dir",
+            deps_str,
+            "-C",
+            "extra-filename=-4567",
+            "--extern",
+            &format!("parser={deps_str}/libparser-0123.rlib"),
+            "--extern",
+            &format!("parser_macros={deps_str}/libparser_macros-89ab.so"),
+        ]);
+        let fuzz_parser = prepare(&fuzz_args).unwrap();
+        let mut contents = fs::read_to_string(&fuzz_parser).unwrap();
+        assert_eq!(
+            contents,
+            format!("# dependency: {deps_str}/parser-0123.dict\n")
+        );
+        contents.push_str("\"GET\"\n\"POST\"\n\"\"\n");
+        fs::write(&fuzz_parser, contents).unwrap();
+
+        finish(&fuzz_args, &fuzz_parser).unwrap();
+        let merged = temp_dir.path().join("debug").join("fuzz_parser.dict");
+        assert_eq!(
+            fs::read_to_string(&merged).unwrap(),
+            "\"GET\"\n\"POST\"\n\"\\x00\\x01\"\n"
+        );
+
+        fs::write(temp_dir.path().join("debug").join("fuzz-parser"), "").unwrap();
+        assert_eq!(
+            for_fuzz_target(&temp_dir.path().join("debug").join("fuzz-parser")),
+            Some(merged)
+        );
+    }
+
+    #[test]
+    fn examples_stay_in_place() {
+        assert_eq!(
+            binary_dir(Path::new("target/debug/examples")),
+            Path::new("target/debug/examples")
+        );
+        assert_eq!(
+            binary_dir(Path::new("target/debug/deps")),
+            Path::new("target/debug")
+        );
+    }
+}
@@ -3,8 +3,23 @@ use clap::{CommandFactory, FromArgMatches, Parser, crate_version};
 use std::collections::HashMap;
 use std::env;
 use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
 use std::process::{self, Command, Stdio};
 
+mod cov;
+mod crash_records;
+mod dictionary;
+mod hash;
+mod manifest;
+mod options;
+mod preflight;
+mod stability;
+mod target_dir;
+mod wrapper;
+
+/// The archive of `afl-compiler-rt.o` that binaries that do not depend on `afl` are linked against
+const RUNTIME_ARCHIVE_NAME: &str = "libafl-compiler-rt.a";
+
 const HELP: &str = "In addition to the subcommands above, Cargo subcommands are also \
 supported (see `cargo help` for a list of all Cargo subcommands).";
 
@@ -103,16 +118,25 @@ declare_afl_subcommand_enum! {
     Analyze("Invoke afl-analyze"),
     Cmin("Invoke afl-cmin"),
     Config("Build, rebuild, or update AFL++", config::Args),
+    Cov("Replay a corpus through a coverage build, and write lcov and HTML reports", cov::Args),
     Fuzz("Invoke afl-fuzz"),
     Gotcpu("Invoke afl-gotcpu"),
     Plot("Invoke afl-plot"),
     Showmap("Invoke afl-showmap"),
+    Stability(
+        "Find the edges whose hit counts vary between runs of the same input, and their source",
+        stability::Args
+    ),
     SystemConfig("Invoke afl-system-config (beware, called with sudo!)"),
     Tmin("Invoke afl-tmin"),
     Whatsup("Invoke afl-whatsup"),
 }
 
 fn main() {
+    if let Some(rustc_args) = wrapper::rustc_args() {
+        wrapper::run(&rustc_args);
+    }
+
     let command = command_with_afl_version();
 
     let afl_args = match Args::from_arg_matches(&command.get_matches()).unwrap() {
@@ -121,15 +145,15 @@ fn main() {
         } => afl_args,
     };
 
-    if !matches!(afl_args.subcmd, Some(AflSubcommand::Config(..)))
-        && !common::object_file_path().unwrap().exists()
+    // Only the AFL++ tools need `cargo afl config --build`. Cargo subcommands (and `cargo afl cov`,
+    // which runs one) get the runtime from the `afl-sys` crate.
+    if afl_args.subcmd.is_some()
+        && !matches!(
+            afl_args.subcmd,
+            Some(AflSubcommand::Config(..) | AflSubcommand::Cov(..))
+        )
     {
-        let version = common::afl_rustc_version().unwrap();
-        eprintln!(
-            "AFL LLVM runtime was not built for Rust {version}; run `cargo \
-             afl config --build` to build it."
-        );
-        process::exit(1);
+        ensure_afl_built();
     }
 
     match &afl_args.subcmd {
@@ -145,6 +169,12 @@ fn main() {
         Some(AflSubcommand::Cmin { args }) => {
             run_afl("afl-cmin", args);
         }
+        Some(AflSubcommand::Cov(args)) => {
+            cov::run(args).unwrap_or_else(|error| {
+                eprintln!("Error: {error:#}");
+                process::exit(1);
+            });
+        }
         Some(AflSubcommand::Fuzz { args }) => {
             // We prepend -c0 to the AFL++ arguments
             let cmplog_flag = [OsString::from("-c0")];
@@ -160,6 +190,12 @@ fn main() {
         Some(AflSubcommand::Showmap { args }) => {
             run_afl("afl-showmap", args);
         }
+        Some(AflSubcommand::Stability(args)) => {
+            stability::run(args).unwrap_or_else(|error| {
+                eprintln!("Error: {error:#}");
+                process::exit(1);
+            });
+        }
         Some(AflSubcommand::SystemConfig { args }) => {
             run_afl("afl-system-config", args);
         }
@@ -175,6 +211,16 @@ fn main() {
     }
 }
 
+fn ensure_afl_built() {
+    if !common::object_file_path().unwrap().exists() {
+        let version = common::afl_rustc_version().unwrap();
+        eprintln!(
+            "AFL++ was not built for Rust {version}; run `cargo afl config --build` to build it."
+        );
+        process::exit(1);
+    }
+}
+
 fn command_with_afl_version() -> clap::Command {
     let mut command = Args::command();
 
@@ -218,6 +264,24 @@ where
 {
     let no_sudo = env::var("NO_SUDO").is_ok();
     let cmd_path = common::afl_dir().unwrap().join("bin").join(tool);
+    // Fuzz targets may be named by the paths they would have without `cargo afl`'s separate target
+    // directory.
+    let args = args
+        .into_iter()
+        .map(|arg| arg.as_ref().to_os_string())
+        .collect::<Vec<_>>();
+    let mut args = target_dir::resolve_fuzz_targets(&args);
+    preflight::check(&args);
+    // Plugin builds write a dictionary next to each fuzz target.
+    if tool == "afl-fuzz"
+        && let Some(dictionary) = args
+            .iter()
+            .filter(|arg| Path::new(arg).is_file())
+            .find_map(|arg| dictionary::for_fuzz_target(Path::new(arg)))
+    {
+        args.splice(0..0, [OsString::from("-x"), dictionary.into_os_string()]);
+    }
+
     let mut cmd = if !no_sudo && tool == "afl-system-config" {
         let mut cmd = Command::new("sudo");
         cmd.args([OsStr::new("--reset-timestamp"), cmd_path.as_os_str()]);
@@ -226,10 +290,42 @@ where
     } else {
         Command::new(cmd_path)
     };
+    // The `afl` crate's watchdog times out just before afl-fuzz does.
+    if tool == "afl-fuzz"
+        && let Some(exec_timeout) = exec_timeout(&args)
+    {
+        cmd.env("AFL_RS_EXEC_TIMEOUT", exec_timeout.to_string());
+    }
+    // The `afl` crate writes a record of each panic, which is copied next to the `crashes`
+    // directory once afl-fuzz exits.
+    let output_and_records_dirs = (tool == "afl-fuzz")
+        .then(|| afl_fuzz_option(&args, "-o"))
+        .flatten()
+        .map(|output_dir| {
+            let output_dir = PathBuf::from(output_dir);
+            let records_dir = crash_records::records_dir(&output_dir);
+            cmd.env(crash_records::RECORDS_DIR_ENV_VAR, &records_dir);
+            // Survive the Ctrl-C that stops afl-fuzz, so that the records can be copied.
+            ignore_interrupts();
+            (output_dir, records_dir)
+        });
     cmd.args(args);
 
     let status = cmd.status().unwrap();
 
+    if let Some((output_dir, records_dir)) = output_and_records_dirs
+        && records_dir.exists()
+    {
+        match crash_records::copy(&output_dir, &records_dir) {
+            Ok(0) => {}
+            Ok(copied) => eprintln!(
+                "Copied {copied} crash record(s) to `{}` directories",
+                crash_records::COPIED_RECORDS_DIR_NAME
+            ),
+            Err(error) => eprintln!("Warning: could not copy crash records: {error:#}"),
+        }
+    }
+
     if tool == "afl-fuzz" && !status.success() {
         eprintln!(
             "
@@ -243,15 +339,57 @@ Note: You might be prompted to enter your password as root privileges are requir
     process::exit(status.code().unwrap_or(1));
 }
 
-fn run_cargo<I, S>(args: I)
-where
-    I: IntoIterator<Item = S>,
-    S: AsRef<OsStr>,
-{
+/// The timeout in milliseconds that afl-fuzz's `-t` sets, if any
+fn exec_timeout(args: &[OsString]) -> Option<u64> {
+    let value = afl_fuzz_option(args, "-t")?;
+    // A trailing `+` makes afl-fuzz skip inputs that time out.
+    value.to_string_lossy().trim_end_matches('+').parse().ok()
+}
+
+/// The value of the afl-fuzz option `flag` (e.g., `-o`), whether it is passed as `-o dir` or
+/// `-odir`
+fn afl_fuzz_option(args: &[OsString], flag: &str) -> Option<OsString> {
+    let mut args = args.iter().take_while(|&arg| arg != "--");
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next().cloned();
+        }
+        if let Some(value) = arg.to_str().and_then(|arg| arg.strip_prefix(flag)) {
+            return Some(OsString::from(value));
+        }
+    }
+    None
+}
+
+/// Keep running when the terminal sends `SIGINT`. Unlike ignoring the signal, installing a handler
+/// does not carry over to the programs `cargo afl` runs.
+fn ignore_interrupts() {
+    #[cfg(unix)]
+    {
+        extern "C" fn handle_interrupt(_: libc::c_int) {}
+        unsafe {
+            libc::signal(
+                libc::SIGINT,
+                handle_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            );
+        }
+    }
+}
+
+fn run_cargo(mut args: Vec<OsString>) {
     #![allow(clippy::similar_names)]
 
     let cargo_path = env::var("CARGO").expect("Could not determine `cargo` path");
 
+    let manifest = manifest::Manifest::load(&args);
+    let options = options::BuildOptions::from_args(&mut args, &manifest).unwrap_or_else(|error| {
+        eprintln!("Error: {error:#}");
+        process::exit(1);
+    });
+
+    let run_target = options::take_run_target(&mut args);
+    let rust_runtime = manifest.uses_rust_runtime(&args);
+
     // add some flags to sanitizers to make them work with Rust code
     let asan_options = env::var("ASAN_OPTIONS").unwrap_or_default();
     let asan_options =
@@ -260,72 +398,78 @@ where
     let tsan_options = env::var("TSAN_OPTIONS").unwrap_or_default();
     let tsan_options = format!("report_signal_unsafe=0:{tsan_options}");
 
-    // The new LLVM pass manager was enabled in rustc 1.59.
-    let version_meta = rustc_version::version_meta().unwrap();
-    let passes = if (version_meta.semver.minor >= 59 || is_nightly())
-        && version_meta.llvm_version.is_none_or(|v| v.major >= 13)
-    {
-        "sancov-module"
-    } else {
-        "sancov"
-    };
-
     let opt_level = env::var("AFL_OPT_LEVEL").unwrap_or("3".to_string());
     let require_plugins = env::var("AFLRS_REQUIRE_PLUGINS").is_ok();
 
     // `-C codegen-units=1` is needed to work around link errors
     // https://github.com/rust-fuzz/afl.rs/pull/193#issuecomment-933550430
 
-    let binding = common::afl_llvm_dir().unwrap();
-    let p = binding.display();
-
     let mut rustflags = format!(
         "-C debug-assertions \
              -C overflow_checks \
              -C codegen-units=1 \
-             -C opt-level={opt_level} \
-             -C target-cpu=native ",
+             -C opt-level={opt_level} ",
     );
+
+    // Without `--target`, `RUSTFLAGS` apply to build scripts and proc macros, which then get
+    // instrumented but not linked against the AFL++ runtime, which only crates that depend on `afl`
+    // are. Only `build.target` prevents that then, which the wrapper checks for.
+    if options.host_target {
+        let host = rustc_version::version_meta().unwrap().host;
+        options::add_target(&mut args, &host);
+    }
+    let require_build_target = !options.host_target && !options::has_target(&args);
+
     let mut environment_variables = HashMap::<&str, String>::new();
+    // A binary built with `afl::targets!` runs the target named by `AFL_RS_TARGET`.
+    if let Some(run_target) = run_target {
+        environment_variables.insert("AFL_RS_TARGET", run_target.to_string_lossy().into_owned());
+    }
     environment_variables.insert("ASAN_OPTIONS", asan_options);
     environment_variables.insert("TSAN_OPTIONS", tsan_options);
 
-    let has_plugins = common::plugins_installed().unwrap();
-    if require_plugins || has_plugins {
-        // Make sure we are on nightly for the -Z flags
-        assert!(
-            rustc_version::version_meta().unwrap().channel == rustc_version::Channel::Nightly,
-            "cargo-afl must be compiled with nightly for CMPLOG and other advanced AFL++ features"
-        );
+    // The `afl` crate records the CPU in the fuzz target so that `cargo afl fuzz` can warn when the
+    // binary will not run on the current host.
+    if let Some(target_cpu) = &options.target_cpu {
+        rustflags.push_str(&format!("-C target-cpu={target_cpu} "));
+        environment_variables.insert("AFL_RS_TARGET_CPU", target_cpu.clone());
+    }
 
-        if require_plugins {
-            assert!(
-                has_plugins,
-                "AFL++ plugins are not installed; run `cargo afl config --build --force --plugins`"
-            );
+    // The Rust runtime does not provide what the plugins' instrumentation calls into.
+    let has_plugins = !rust_runtime && common::plugins_installed().unwrap();
+    if rust_runtime && require_plugins {
+        eprintln!("Error: the `afl` crate's `rust_runtime` feature does not support AFL++ plugins");
+        process::exit(1);
+    }
+    let instrumentation_rustflags = instrumentation_rustflags(require_plugins, has_plugins);
+    if options.crate_filter.is_empty() && !require_build_target {
+        rustflags.push_str(&instrumentation_rustflags);
+    } else {
+        wrapper::configure(
+            &mut environment_variables,
+            &options.crate_filter,
+            &instrumentation_rustflags,
+            options::has_target(&args) || require_build_target,
+        );
+        if require_build_target {
+            wrapper::require_build_target(&mut environment_variables);
         }
-
-        rustflags.push_str(&format!(
-            "-Z llvm-plugins={p}/afl-llvm-dict2file.so \
-            -Z llvm-plugins={p}/cmplog-switches-pass.so \
-            -Z llvm-plugins={p}/split-switches-pass.so \
-            -Z llvm-plugins={p}/SanitizerCoveragePCGUARD.so \
-            -Z llvm-plugins={p}/cmplog-instructions-pass.so  \
-            -Z llvm-plugins={p}/cmplog-routines-pass.so \
-            -Z llvm-plugins={p}/afl-llvm-ijon-pass.so
-            "
-        ));
-
+    }
+    if require_plugins || has_plugins {
         environment_variables.insert("AFL_QUIET", "1".to_string());
-    } else {
-        rustflags.push_str(&format!(
-            "-C passes={passes} \
-            -C llvm-args=-sanitizer-coverage-level=3 \
-            -C llvm-args=-sanitizer-coverage-trace-pc-guard \
-            -C llvm-args=-sanitizer-coverage-prune-blocks=0 \
-            -C llvm-args=-sanitizer-coverage-trace-compares
-            ",
-        ));
+        // Collect what `afl-llvm-dict2file` finds into a dictionary per fuzz target, unless the
+        // user wants the plugin's output elsewhere.
+        if env::var_os("AFL_LLVM_DICT2FILE").is_none() {
+            wrapper::collect_dictionaries(&mut environment_variables);
+        }
+    }
+
+    // Put instrumented builds in their own target directory so that they do not invalidate normal
+    // ones, unless the user chose a directory.
+    if let Some(afl_target_dir) =
+        target_dir::for_build(&args, &manifest, &options, require_plugins || has_plugins)
+    {
+        environment_variables.insert("CARGO_TARGET_DIR", afl_target_dir.display().to_string());
     }
 
     let no_cfg_fuzzing = env::var("AFL_NO_CFG_FUZZING").is_ok();
@@ -344,10 +488,11 @@ where
     // have doctests built the same as other code to avoid issues with doctests.
     let mut rustdocflags = rustflags.clone();
 
-    rustflags.push_str(&format!(
-        "-Clink-arg={} ",
-        common::object_file_path().unwrap().display()
-    ));
+    // Binaries that depend on `afl` get the runtime from `afl-sys` or the Rust runtime, but other
+    // instrumented binaries (e.g., unit tests) need it too.
+    if let Some(archive) = runtime_archive() {
+        rustflags.push_str(&format!("-Clink-arg={} ", archive.display()));
+    }
 
     // add user provided flags
     rustflags.push_str(&env::var("RUSTFLAGS").unwrap_or_default());
@@ -364,6 +509,99 @@ where
     process::exit(status.code().unwrap_or(1));
 }
 
+/// An archive of the `afl-compiler-rt.o` that `cargo afl config --build` built, created next to it
+/// if needed, or `None` if AFL++ is not built
+///
+/// Unlike the object file itself, an archive's members are linked only into binaries that still
+/// have undefined symbols after their own libraries, so binaries that get the runtime from the
+/// `afl` crate do not end up with two copies of it.
+fn runtime_archive() -> Option<PathBuf> {
+    let object = common::object_file_path().ok()?;
+    let modified = object
+        .metadata()
+        .and_then(|metadata| metadata.modified())
+        .ok()?;
+    let archive = object.with_file_name(RUNTIME_ARCHIVE_NAME);
+    if archive
+        .metadata()
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|archive_modified| archive_modified >= modified)
+    {
+        return Some(archive);
+    }
+    // Build the archive under a name of its own, so that concurrent builds do not see it
+    // half-written.
+    let partial = archive.with_extension(format!("{}.a", process::id()));
+    let ar = env::var_os("AR").unwrap_or_else(|| OsString::from("ar"));
+    let success = Command::new(ar)
+        .arg("rcs")
+        .arg(&partial)
+        .arg(&object)
+        .status()
+        .is_ok_and(|status| status.success());
+    if !success || std::fs::rename(&partial, &archive).is_err() {
+        let _ = std::fs::remove_file(&partial);
+        eprintln!(
+            "Warning: could not create `{}`; instrumented binaries that do not depend on `afl` will \
+             fail to link",
+            archive.display()
+        );
+        return None;
+    }
+    Some(archive)
+}
+
+/// The flags that make rustc insert coverage instrumentation
+fn instrumentation_rustflags(require_plugins: bool, has_plugins: bool) -> String {
+    if require_plugins || has_plugins {
+        // Make sure we are on nightly for the -Z flags
+        assert!(
+            rustc_version::version_meta().unwrap().channel == rustc_version::Channel::Nightly,
+            "cargo-afl must be compiled with nightly for CMPLOG and other advanced AFL++ features"
+        );
+
+        if require_plugins {
+            assert!(
+                has_plugins,
+                "AFL++ plugins are not installed; run `cargo afl config --build --force --plugins`"
+            );
+        }
+
+        let binding = common::afl_llvm_dir().unwrap();
+        let p = binding.display();
+
+        format!(
+            "-Z llvm-plugins={p}/afl-llvm-dict2file.so \
+            -Z llvm-plugins={p}/cmplog-switches-pass.so \
+            -Z llvm-plugins={p}/split-switches-pass.so \
+            -Z llvm-plugins={p}/SanitizerCoveragePCGUARD.so \
+            -Z llvm-plugins={p}/cmplog-instructions-pass.so  \
+            -Z llvm-plugins={p}/cmplog-routines-pass.so \
+            -Z llvm-plugins={p}/afl-llvm-ijon-pass.so
+            "
+        )
+    } else {
+        // The new LLVM pass manager was enabled in rustc 1.59.
+        let version_meta = rustc_version::version_meta().unwrap();
+        let passes = if (version_meta.semver.minor >= 59 || is_nightly())
+            && version_meta.llvm_version.is_none_or(|v| v.major >= 13)
+        {
+            "sancov-module"
+        } else {
+            "sancov"
+        };
+
+        format!(
+            "-C passes={passes} \
+            -C llvm-args=-sanitizer-coverage-level=3 \
+            -C llvm-args=-sanitizer-coverage-trace-pc-guard \
+            -C llvm-args=-sanitizer-coverage-prune-blocks=0 \
+            -C llvm-args=-sanitizer-coverage-trace-compares
+            ",
+        )
+    }
+}
+
 fn is_nightly() -> bool {
     Command::new("rustc")
         .args(["-Z", "help"])
@@ -434,6 +672,19 @@ mod tests {
             Args::try_parse_from(["cargo", "afl", subcommand, "-i", "--input"]).unwrap();
     }
 
+    #[test]
+    fn exec_timeout_is_parsed() {
+        for (args, expected) in [
+            (&["-i", "in", "-t", "500", "target"][..], Some(500)),
+            (&["-t2000+", "target"], Some(2000)),
+            (&["-i", "in", "target"], None),
+            (&["-i", "in", "--", "target", "-t", "500"], None),
+        ] {
+            let args = args.iter().map(OsString::from).collect::<Vec<_>>();
+            assert_eq!(exec_timeout(&args), expected);
+        }
+    }
+
     fn invalid_utf8() -> OsString {
         OsString::from_vec(vec![0xfe])
     }
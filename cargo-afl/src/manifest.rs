@@ -0,0 +1,165 @@
+//! Settings read from `[package.metadata.afl]` and `[workspace.metadata.afl]`
+
+use anyhow::{Result, bail};
+use cargo_metadata::{Metadata, MetadataCommand};
+use serde_json::Value;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+const RUST_RUNTIME_FEATURE: &str = "rust_runtime";
+
+pub struct Manifest {
+    metadata: Option<Metadata>,
+    package: Option<String>,
+}
+
+impl Manifest {
+    /// Run `cargo metadata` for the package that `args` refer to.
+    ///
+    /// Failures are not fatal: not every Cargo subcommand runs inside a package (e.g., `cargo afl
+    /// new`), and such commands simply see no settings.
+    pub fn load(args: &[OsString]) -> Self {
+        let mut command = MetadataCommand::new();
+        command.no_deps();
+        if let Some(manifest_path) = cargo_option(args, "--manifest-path", None) {
+            command.manifest_path(manifest_path);
+        }
+        Self {
+            metadata: command.exec().ok(),
+            package: cargo_option(args, "--package", Some("-p")),
+        }
+    }
+
+    /// Cargo's target directory, taking `CARGO_TARGET_DIR` and Cargo's configuration into account
+    pub fn target_directory(&self) -> Option<PathBuf> {
+        self.metadata
+            .as_ref()
+            .map(|metadata| metadata.target_directory.clone().into_std_path_buf())
+    }
+
+    /// The package containing the binary or example named `name`, and the Cargo flag that selects
+    /// it (`--bin` or `--example`)
+    pub fn binary_target(&self, name: &str) -> Option<(String, &'static str)> {
+        self.metadata
+            .as_ref()?
+            .workspace_packages()
+            .into_iter()
+            .find_map(|package| {
+                let target = package.targets.iter().find(|target| {
+                    target.name == name && (target.is_bin() || target.is_example())
+                })?;
+                let flag = if target.is_example() {
+                    "--example"
+                } else {
+                    "--bin"
+                };
+                Some((package.name.to_string(), flag))
+            })
+    }
+
+    /// Whether the build uses the `afl` crate's `rust_runtime` feature instead of
+    /// `afl-compiler-rt.o`, either through a dependency declaration or through `--features`
+    pub fn uses_rust_runtime(&self, args: &[OsString]) -> bool {
+        let is_rust_runtime =
+            |feature: &str| feature == RUST_RUNTIME_FEATURE || feature == "afl/rust_runtime";
+        let mut iter = args
+            .iter()
+            .map(|arg| arg.to_string_lossy())
+            .take_while(|arg| arg != "--");
+        while let Some(arg) = iter.next() {
+            let features = if arg == "--features" || arg == "-F" {
+                iter.next().map(String::from)
+            } else {
+                arg.strip_prefix("--features=").map(ToOwned::to_owned)
+            };
+            if features.is_some_and(|features| features.split([',', ' ']).any(is_rust_runtime)) {
+                return true;
+            }
+        }
+        self.metadata.as_ref().is_some_and(|metadata| {
+            metadata
+                .workspace_packages()
+                .iter()
+                .flat_map(|package| &package.dependencies)
+                .filter(|dependency| dependency.name == "afl")
+                .any(|dependency| {
+                    dependency
+                        .features
+                        .iter()
+                        .any(|feature| feature == RUST_RUNTIME_FEATURE)
+                })
+        })
+    }
+
+    /// Look up `key`, preferring the package's table over the workspace's.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        let metadata = self.metadata.as_ref()?;
+        let package = if let Some(name) = &self.package {
+            metadata
+                .workspace_packages()
+                .into_iter()
+                .find(|package| package.name.as_str() == name)
+        } else {
+            metadata.root_package()
+        };
+        package
+            .and_then(|package| package.metadata.get("afl"))
+            .and_then(|table| table.get(key))
+            .or_else(|| {
+                metadata
+                    .workspace_metadata
+                    .get("afl")
+                    .and_then(|table| table.get(key))
+            })
+    }
+
+    pub fn bool(&self, key: &str) -> Result<Option<bool>> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::Bool(value)) => Ok(Some(*value)),
+            Some(_) => bail!("`metadata.afl.{key}` must be a boolean"),
+        }
+    }
+
+    pub fn strings(&self, key: &str) -> Result<Vec<String>> {
+        match self.get(key) {
+            None => Ok(Vec::new()),
+            Some(Value::Array(values)) => values
+                .iter()
+                .map(|value| match value {
+                    Value::String(value) => Ok(value.clone()),
+                    _ => bail!("`metadata.afl.{key}` must be an array of strings"),
+                })
+                .collect(),
+            Some(_) => bail!("`metadata.afl.{key}` must be an array of strings"),
+        }
+    }
+
+    pub fn string(&self, key: &str) -> Result<Option<String>> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::String(value)) => Ok(Some(value.clone())),
+            Some(_) => bail!("`metadata.afl.{key}` must be a string"),
+        }
+    }
+}
+
+/// Find the value of a Cargo option without removing it from `args`
+pub fn cargo_option(args: &[OsString], long: &str, short: Option<&str>) -> Option<String> {
+    let mut iter = args
+        .iter()
+        .map(|arg| arg.to_string_lossy())
+        .take_while(|arg| arg != "--");
+    while let Some(arg) = iter.next() {
+        if arg == long || short.is_some_and(|short| arg == short) {
+            return iter.next().map(Into::into);
+        }
+        if let Some(value) = arg
+            .strip_prefix(long)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            return Some(value.to_owned());
+        }
+    }
+    None
+}
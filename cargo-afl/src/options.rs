@@ -0,0 +1,306 @@
+//! Build options that `cargo afl` accepts in addition to Cargo's own
+//!
+//! Each option can be passed on the command line, where it is removed before the remaining
+//! arguments are handed to Cargo, or set in `[package.metadata.afl]`/`[workspace.metadata.afl]`.
+//! The command line takes precedence.
+
+use crate::{manifest::Manifest, wrapper::CrateFilter};
+use anyhow::{Result, bail, ensure};
+use std::env;
+use std::ffi::OsString;
+
+const DEFAULT_TARGET_CPU: &str = "native";
+
+pub struct BuildOptions {
+    /// CPU to generate code for, or `None` to use rustc's default for the target (`--portable`)
+    pub target_cpu: Option<String>,
+    /// Whether to pass the host triple as `--target` when the user does not pass one, so that
+    /// `RUSTFLAGS` (and thus instrumentation) do not apply to build scripts and proc macros
+    pub host_target: bool,
+    /// Which crates to instrument
+    pub crate_filter: CrateFilter,
+}
+
+impl BuildOptions {
+    pub fn from_args(args: &mut Vec<OsString>, manifest: &Manifest) -> Result<Self> {
+        let portable = take_flag(args, "--portable");
+        let target_cpu = take_value(args, "--target-cpu")?;
+        ensure!(
+            !portable || target_cpu.is_none(),
+            "`--portable` and `--target-cpu` cannot be used together"
+        );
+
+        let target_cpu = if portable {
+            None
+        } else if target_cpu.is_some() {
+            target_cpu
+        } else if manifest.bool("portable")?.unwrap_or_default() {
+            None
+        } else {
+            Some(
+                manifest
+                    .string("target-cpu")?
+                    .unwrap_or_else(|| DEFAULT_TARGET_CPU.to_owned()),
+            )
+        };
+
+        let host_target =
+            !take_flag(args, "--no-host-target") && manifest.bool("host-target")?.unwrap_or(true);
+
+        let crate_filter = CrateFilter {
+            instrument: values_or_manifest(take_values(args, "--instrument")?, || {
+                manifest.strings("instrument")
+            })?,
+            no_instrument: values_or_manifest(take_values(args, "--no-instrument")?, || {
+                manifest.strings("no-instrument")
+            })?,
+        };
+
+        Ok(Self {
+            target_cpu,
+            host_target,
+            crate_filter,
+        })
+    }
+}
+
+fn values_or_manifest(
+    values: Vec<String>,
+    manifest_values: impl FnOnce() -> Result<Vec<String>>,
+) -> Result<Vec<String>> {
+    if values.is_empty() {
+        manifest_values()
+    } else {
+        Ok(values)
+    }
+}
+
+/// Cargo subcommands that accept `--target`
+const SUBCOMMANDS_WITH_TARGET: &[&str] = &[
+    "b", "bench", "build", "c", "check", "clean", "clippy", "d", "doc", "fix", "r", "run", "rustc",
+    "rustdoc", "t", "test",
+];
+
+/// Insert `--target <triple>` after the Cargo subcommand in `args`, unless the subcommand does not
+/// accept `--target` or a target was already chosen
+pub fn add_target(args: &mut Vec<OsString>, triple: &str) {
+    if has_target(args) {
+        return;
+    }
+    let options = &args[..end_of_options(args)];
+    let Some(index) = options
+        .iter()
+        .position(|arg| !arg.to_string_lossy().starts_with('-'))
+    else {
+        return;
+    };
+    if !SUBCOMMANDS_WITH_TARGET.contains(&&*args[index].to_string_lossy()) {
+        return;
+    }
+    args.insert(index + 1, OsString::from(triple));
+    args.insert(index + 1, OsString::from("--target"));
+}
+
+/// Options of `cargo run` that take a value as a separate argument
+const RUN_OPTIONS_WITH_VALUES: &[&str] = &[
+    "-F",
+    "-j",
+    "-p",
+    "-Z",
+    "--bin",
+    "--color",
+    "--config",
+    "--example",
+    "--features",
+    "--jobs",
+    "--lockfile-path",
+    "--manifest-path",
+    "--message-format",
+    "--package",
+    "--profile",
+    "--target",
+    "--target-dir",
+];
+
+/// If the Cargo subcommand in `args` is `run` and it is followed by a positional argument, remove
+/// that argument and return it. It names the target to run in a binary built with `afl::targets!`.
+pub fn take_run_target(args: &mut Vec<OsString>) -> Option<OsString> {
+    let end = end_of_options(args);
+    let subcommand = args[..end]
+        .iter()
+        .position(|arg| !arg.to_string_lossy().starts_with('-'))?;
+    if !["r", "run"].contains(&&*args[subcommand].to_string_lossy()) {
+        return None;
+    }
+    let mut index = subcommand + 1;
+    while index < end {
+        let arg = args[index].to_string_lossy();
+        if RUN_OPTIONS_WITH_VALUES.contains(&&*arg) {
+            index += 2;
+        } else if arg.starts_with('-') {
+            index += 1;
+        } else {
+            return Some(args.remove(index));
+        }
+    }
+    None
+}
+
+/// Remove every occurrence of `--name` from `args`, and return whether there was one
+fn take_flag(args: &mut Vec<OsString>, name: &str) -> bool {
+    let end = end_of_options(args);
+    let len = args.len();
+    let mut index = 0;
+    args.retain(|arg| {
+        index += 1;
+        index > end || arg != name
+    });
+    args.len() != len
+}
+
+/// Remove the last occurrence of `--name <value>` or `--name=<value>` from `args`, and return its
+/// value
+fn take_value(args: &mut Vec<OsString>, name: &str) -> Result<Option<String>> {
+    Ok(take_values(args, name)?.pop())
+}
+
+/// Remove every occurrence of `--name <value>` or `--name=<value>` from `args`, and return their
+/// values
+fn take_values(args: &mut Vec<OsString>, name: &str) -> Result<Vec<String>> {
+    let mut values = Vec::new();
+    let mut index = 0;
+    while index < end_of_options(args) {
+        let Some(arg) = args[index].to_str() else {
+            index += 1;
+            continue;
+        };
+        if arg == name {
+            args.remove(index);
+            if index >= end_of_options(args) {
+                bail!("`{name}` requires a value");
+            }
+            let value = args.remove(index);
+            let Ok(value) = value.into_string() else {
+                bail!("the value of `{name}` is not valid UTF-8");
+            };
+            values.push(value);
+        } else if let Some(value) = arg.strip_prefix(name).and_then(|s| s.strip_prefix('=')) {
+            values.push(value.to_owned());
+            args.remove(index);
+        } else {
+            index += 1;
+        }
+    }
+    Ok(values)
+}
+
+/// Whether `args` (or the environment) choose a target
+pub fn has_target(args: &[OsString]) -> bool {
+    args[..end_of_options(args)]
+        .iter()
+        .filter_map(|arg| arg.to_str())
+        .any(|arg| arg == "--target" || arg.starts_with("--target="))
+        || env::var_os("CARGO_BUILD_TARGET").is_some()
+}
+
+/// Index of the `--` that separates Cargo's arguments from the ones it forwards, or `args.len()`
+fn end_of_options(args: &[OsString]) -> usize {
+    args.iter()
+        .position(|arg| arg == "--")
+        .unwrap_or(args.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn flags_are_removed() {
+        let mut args = args(&["build", "--portable", "--release", "--", "--portable"]);
+        assert!(take_flag(&mut args, "--portable"));
+        assert_eq!(
+            args,
+            self::args(&["build", "--release", "--", "--portable"])
+        );
+        assert!(!take_flag(&mut args, "--portable"));
+    }
+
+    #[test]
+    fn values_are_removed() {
+        let mut args = args(&[
+            "build",
+            "--target-cpu",
+            "x86-64-v2",
+            "--target-cpu=x86-64-v3",
+            "--",
+            "--target-cpu=x86-64-v4",
+        ]);
+        assert_eq!(
+            take_value(&mut args, "--target-cpu").unwrap().as_deref(),
+            Some("x86-64-v3")
+        );
+        assert_eq!(args, self::args(&["build", "--", "--target-cpu=x86-64-v4"]));
+    }
+
+    #[test]
+    fn target_is_added() {
+        let mut args = args(&["build", "--example", "hello"]);
+        add_target(&mut args, "x86_64-unknown-linux-gnu");
+        assert_eq!(
+            args,
+            self::args(&[
+                "build",
+                "--target",
+                "x86_64-unknown-linux-gnu",
+                "--example",
+                "hello"
+            ])
+        );
+    }
+
+    #[test]
+    fn target_is_not_added() {
+        for original in [
+            &["build", "--target=wasm32-unknown-unknown"][..],
+            &["tree"],
+            &["-q", "metadata", "--", "build"],
+        ] {
+            let mut args = args(original);
+            add_target(&mut args, "x86_64-unknown-linux-gnu");
+            assert_eq!(args, self::args(original));
+        }
+    }
+
+    #[test]
+    fn run_target_is_removed() {
+        let mut args = args(&["run", "--example", "targets", "-q", "utf8", "--", "input"]);
+        assert_eq!(take_run_target(&mut args), Some(OsString::from("utf8")));
+        assert_eq!(
+            args,
+            self::args(&["run", "--example", "targets", "-q", "--", "input"])
+        );
+    }
+
+    #[test]
+    fn run_target_is_not_removed() {
+        for original in [
+            &["run", "--example", "targets", "--", "utf8"][..],
+            &["build", "--example", "targets"],
+            &["test", "utf8"],
+        ] {
+            let mut args = args(original);
+            assert_eq!(take_run_target(&mut args), None);
+            assert_eq!(args, self::args(original));
+        }
+    }
+
+    #[test]
+    fn missing_value() {
+        let mut args = args(&["build", "--target-cpu", "--", "x"]);
+        assert!(take_value(&mut args, "--target-cpu").is_err());
+    }
+}
@@ -0,0 +1,130 @@
+//! Checks run on a fuzz target before it is handed to an AFL++ tool
+
+use anyhow::{Context, Result, ensure};
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Command;
+
+/// Written into every fuzz target by the `afl` crate; see `TARGET_CPU_MARKER` in
+/// `afl/src/inputs.rs`
+const TARGET_CPU_MARKER: &[u8] = b"##AFL_RS_TARGET_CPU:";
+const MARKER_END: &[u8] = b"##";
+
+/// The CPU a fuzz target was compiled for
+#[derive(Debug, PartialEq, Eq)]
+struct TargetCpu {
+    name: String,
+    features: BTreeSet<String>,
+}
+
+/// Warn about every fuzz target among `args` that cannot run on this host.
+///
+/// Problems running the check itself are reported but do not stop the AFL++ tool from running.
+pub fn check(args: &[OsString]) {
+    for arg in args {
+        let path = Path::new(arg);
+        if !path.is_file() {
+            continue;
+        }
+        let Ok(contents) = std::fs::read(path) else {
+            continue;
+        };
+        let Some(target_cpu) = target_cpu(&contents) else {
+            continue;
+        };
+        if let Err(error) = check_target_cpu(path, &target_cpu) {
+            eprintln!(
+                "Warning: could not check whether `{}` runs on this host: {error:#}",
+                path.display()
+            );
+        }
+    }
+}
+
+fn check_target_cpu(path: &Path, target_cpu: &TargetCpu) -> Result<()> {
+    let host_features = host_target_features()?;
+    let missing = target_cpu
+        .features
+        .difference(&host_features)
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        eprintln!(
+            "Warning: `{}` was built for CPU `{}`, but this host lacks the following target \
+             features: {}\nThe fuzz target will likely crash with SIGILL. Rebuild it with `cargo \
+             afl build --portable` or `cargo afl build --target-cpu <CPU>`.",
+            path.display(),
+            target_cpu.name,
+            missing.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Extract the CPU recorded in a fuzz target's binary, if any
+fn target_cpu(contents: &[u8]) -> Option<TargetCpu> {
+    let start = find(contents, TARGET_CPU_MARKER)? + TARGET_CPU_MARKER.len();
+    let len = find(&contents[start..], MARKER_END)?;
+    let record = std::str::from_utf8(&contents[start..start + len]).ok()?;
+    let (name, features) = record.split_once(':')?;
+    // Builds that did not go through `cargo afl build` record no CPU.
+    if name.is_empty() {
+        return None;
+    }
+    Some(TargetCpu {
+        name: name.to_owned(),
+        features: features
+            .split(',')
+            .filter(|feature| !feature.is_empty())
+            .map(ToOwned::to_owned)
+            .collect(),
+    })
+}
+
+fn host_target_features() -> Result<BTreeSet<String>> {
+    let output = Command::new("rustc")
+        .args(["--print", "cfg", "-C", "target-cpu=native"])
+        .output()
+        .with_context(|| "could not run `rustc --print cfg`")?;
+    ensure!(output.status.success(), "`rustc --print cfg` failed");
+    let stdout = String::from_utf8(output.stdout)?;
+    Ok(stdout
+        .lines()
+        .filter_map(|line| line.strip_prefix("target_feature=\""))
+        .filter_map(|line| line.strip_suffix('"'))
+        .map(ToOwned::to_owned)
+        .collect())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_cpu_is_extracted() {
+        let contents = b"\x7fELF...##AFL_RS_TARGET_CPU:x86-64-v2:cmpxchg16b,popcnt,sse3##\0...";
+        assert_eq!(
+            target_cpu(contents),
+            Some(TargetCpu {
+                name: String::from("x86-64-v2"),
+                features: ["cmpxchg16b", "popcnt", "sse3"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+            })
+        );
+    }
+
+    #[test]
+    fn unrecorded_target_cpu_is_ignored() {
+        assert_eq!(target_cpu(b"##AFL_RS_TARGET_CPU::sse2##\0"), None);
+        assert_eq!(target_cpu(b"no marker"), None);
+    }
+}
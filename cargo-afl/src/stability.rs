@@ -0,0 +1,443 @@
+//! `cargo afl stability`, which finds the edges whose hit counts vary when the same input is run
+//! again, and the source locations they belong to
+//!
+//! Each input in the corpus is copied several times, and afl-showmap runs every copy in one pass,
+//! so that state left behind by one iteration shows up in the next, as it does under afl-fuzz. An
+//! edge is unstable if its (bucketed) hit count differs between the copies of some input.
+//!
+//! Edge IDs are mapped back to source as follows. The instrumentation gives each edge a guard in
+//! the `__sancov_guards` section, and the runtime numbers the guards in section order. The
+//! instruction that refers to an edge's guard is found by disassembling the binary with `objdump`,
+//! and `addr2line` gives its function and line. This relies on the operand annotations of x86
+//! disassembly, so other architectures are rejected.
+
+use crate::{preflight, target_dir};
+use anyhow::{Context, Result, bail, ensure};
+use clap::Parser;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::OsString;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{BufRead, BufReader, Write as _};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+const GUARDS_SECTION: &str = "__sancov_guards";
+
+/// Defined by AFL++'s runtime, which numbers guards from 5; the `afl` crate's Rust runtime numbers
+/// them from 1
+const AFLPLUSPLUS_RUNTIME_SYMBOL: &str = "__afl_final_loc";
+
+#[derive(Parser)]
+#[clap(after_help = "\
+Example:
+
+    cargo afl stability -i out/default/queue target/debug/my_fuzz_target")]
+pub struct Args {
+    #[clap(
+        short = 'i',
+        value_name = "DIR",
+        help = "Directory of inputs (e.g., a queue)"
+    )]
+    input: PathBuf,
+
+    #[clap(
+        long,
+        default_value_t = 8,
+        value_parser = clap::value_parser!(u32).range(2..),
+        help = "How many times to run each input"
+    )]
+    runs: u32,
+
+    #[clap(
+        long,
+        default_value_t = 20,
+        help = "How many unstable locations to show"
+    )]
+    top: usize,
+
+    #[clap(
+        short = 't',
+        value_name = "MS",
+        help = "Timeout for each run, passed to afl-showmap"
+    )]
+    timeout: Option<String>,
+
+    #[clap(
+        required = true,
+        allow_hyphen_values = true,
+        trailing_var_arg = true,
+        value_name = "TARGET",
+        help = "The fuzz target, followed by its arguments (e.g., `@@`)"
+    )]
+    target: Vec<OsString>,
+}
+
+pub fn run(args: &Args) -> Result<()> {
+    let inputs = inputs(&args.input)?;
+    ensure!(
+        !inputs.is_empty(),
+        "`{}` contains no inputs",
+        args.input.display()
+    );
+
+    let temp_dir = tempfile::tempdir()?;
+    let copies_dir = temp_dir.path().join("inputs");
+    let maps_dir = temp_dir.path().join("maps");
+    fs::create_dir(&copies_dir)?;
+    for (index, input) in inputs.iter().enumerate() {
+        for run in 0..args.runs {
+            fs::copy(input, copies_dir.join(copy_name(index, run)))?;
+        }
+    }
+
+    let mut showmap_args = vec![
+        OsString::from("-i"),
+        copies_dir.into_os_string(),
+        OsString::from("-o"),
+        maps_dir.clone().into_os_string(),
+    ];
+    if let Some(timeout) = &args.timeout {
+        showmap_args.extend([OsString::from("-t"), OsString::from(timeout)]);
+    }
+    showmap_args.push(OsString::from("--"));
+    showmap_args.extend(args.target.iter().cloned());
+    let showmap_args = target_dir::resolve_fuzz_targets(&showmap_args);
+    preflight::check(&showmap_args);
+    let binary =
+        PathBuf::from(&showmap_args[showmap_args.iter().position(|arg| arg == "--").unwrap() + 1]);
+
+    eprintln!(
+        "Running {} inputs {} times each with afl-showmap...",
+        inputs.len(),
+        args.runs
+    );
+    let output = Command::new(cargo_afl_common::afl_dir()?.join("bin/afl-showmap"))
+        .args(&showmap_args)
+        .output()
+        .context("could not run afl-showmap")?;
+    if !maps_dir.is_dir() {
+        bail!(
+            "afl-showmap did not write any coverage maps:\n{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    let mut edges = BTreeSet::<u32>::new();
+    let mut unstable = BTreeMap::<u32, usize>::new();
+    let mut compared = 0;
+    for index in 0..inputs.len() {
+        let maps = (0..args.runs)
+            .filter_map(|run| fs::read_to_string(maps_dir.join(copy_name(index, run))).ok())
+            .map(|map| parse_map(&map))
+            .collect::<Vec<_>>();
+        if maps.len() < 2 {
+            continue;
+        }
+        compared += 1;
+        edges.extend(maps.iter().flat_map(BTreeMap::keys).copied());
+        for edge in unstable_edges(&maps) {
+            *unstable.entry(edge).or_default() += 1;
+        }
+    }
+    ensure!(
+        compared != 0,
+        "afl-showmap did not write a coverage map for any input; does the target run?"
+    );
+
+    #[allow(clippy::cast_precision_loss)]
+    let stability = 100.0 * (1.0 - unstable.len() as f64 / edges.len().max(1) as f64);
+    println!(
+        "{} of {} edges varied across {compared} inputs ({stability:.2}% stable)",
+        unstable.len(),
+        edges.len(),
+    );
+    if unstable.is_empty() {
+        return Ok(());
+    }
+
+    let locations = locations(&binary, &unstable.keys().copied().collect::<Vec<_>>())
+        .unwrap_or_else(|error| {
+            eprintln!("Warning: could not map edges to source locations: {error:#}");
+            HashMap::new()
+        });
+    print!("{}", report(&unstable, &locations, args.top));
+    Ok(())
+}
+
+/// The regular files in `dir`, other than hidden ones (e.g., afl-fuzz's `.state`)
+pub fn inputs(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut inputs = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("could not read `{}`", dir.display()))? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() && !entry.file_name().to_string_lossy().starts_with('.') {
+            inputs.push(entry.path());
+        }
+    }
+    inputs.sort();
+    Ok(inputs)
+}
+
+fn copy_name(index: usize, run: u32) -> String {
+    format!("{index:06}-{run:03}")
+}
+
+/// Parse afl-showmap's output, which has a line `<edge>:<count>` for each edge that was hit
+fn parse_map(map: &str) -> BTreeMap<u32, u32> {
+    map.lines()
+        .filter_map(|line| {
+            let (edge, count) = line.split_once(':')?;
+            Some((edge.parse().ok()?, count.parse().ok()?))
+        })
+        .collect()
+}
+
+/// The edges whose counts are not the same in all of `maps`
+fn unstable_edges(maps: &[BTreeMap<u32, u32>]) -> BTreeSet<u32> {
+    maps.iter()
+        .flat_map(BTreeMap::keys)
+        .filter(|edge| {
+            let count = maps[0].get(edge);
+            maps[1..].iter().any(|map| map.get(edge) != count)
+        })
+        .copied()
+        .collect()
+}
+
+/// The source location of each of `edges`, as `<function> at <file>:<line>`
+fn locations(binary: &Path, edges: &[u32]) -> Result<HashMap<u32, String>> {
+    let file_headers = run_tool(Command::new("objdump").arg("-f").arg(binary))?;
+    let architecture = architecture(&file_headers).unwrap_or("an unknown architecture");
+    ensure!(
+        is_x86(architecture),
+        "mapping edges to source is only supported on x86, not on {architecture}"
+    );
+    let headers = run_tool(Command::new("objdump").arg("-h").arg(binary))?;
+    let Some((guards_start, guards_size)) = guards_section(&headers) else {
+        bail!(
+            "`{}` has no `{GUARDS_SECTION}` section; was it built with `cargo afl build`?",
+            binary.display()
+        );
+    };
+    let symbols = run_tool(Command::new("objdump").arg("-t").arg(binary))?;
+    let first_id = if symbols.contains(AFLPLUSPLUS_RUNTIME_SYMBOL) {
+        5
+    } else {
+        1
+    };
+
+    // The address of the first instruction that refers to each guard, by guard index
+    let mut instructions = HashMap::new();
+    let mut disassembly = Command::new("objdump")
+        .args(["-d", "--no-show-raw-insn"])
+        .arg(binary)
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("could not run `objdump`")?;
+    for line in BufReader::new(disassembly.stdout.take().unwrap()).lines() {
+        let Some((address, target)) = guard_reference(&line?) else {
+            continue;
+        };
+        if (guards_start..guards_start + guards_size).contains(&target) {
+            instructions
+                .entry((target - guards_start) / 4)
+                .or_insert(address);
+        }
+    }
+    disassembly.wait()?;
+    ensure!(
+        !instructions.is_empty(),
+        "no instruction in `{}` refers to a guard",
+        binary.display()
+    );
+
+    let edges = edges
+        .iter()
+        .filter_map(|&edge| {
+            let index = u64::from(edge.checked_sub(first_id)?);
+            Some((edge, *instructions.get(&index)?))
+        })
+        .collect::<Vec<_>>();
+    let mut addresses = String::new();
+    for (_, address) in &edges {
+        let _ = writeln!(addresses, "{address:#x}");
+    }
+    let mut addr2line = Command::new("addr2line")
+        .args(["-f", "-C", "-e"])
+        .arg(binary)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("could not run `addr2line`")?;
+    addr2line
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(addresses.as_bytes())?;
+    let output = addr2line.wait_with_output()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    // `addr2line -f` prints the function, then the file and line, for each address.
+    let mut lines = output.lines();
+    Ok(edges
+        .into_iter()
+        .filter_map(|(edge, _)| {
+            let function = lines.next()?;
+            let file_line = lines.next()?;
+            Some((edge, format!("{function} at {file_line}")))
+        })
+        .collect())
+}
+
+pub fn run_tool(command: &mut Command) -> Result<String> {
+    let output = command
+        .output()
+        .with_context(|| format!("could not run `{}`", command.get_program().display()))?;
+    ensure!(
+        output.status.success(),
+        "`{}` failed: {}",
+        command.get_program().display(),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The architecture named by the output of `objdump -f` (e.g., `i386:x86-64`)
+fn architecture(file_headers: &str) -> Option<&str> {
+    file_headers.lines().find_map(|line| {
+        let architecture = line.strip_prefix("architecture: ")?;
+        Some(architecture.split(',').next()?.trim())
+    })
+}
+
+/// Whether `architecture`, as objdump names it, is 32- or 64-bit x86
+fn is_x86(architecture: &str) -> bool {
+    architecture.starts_with("i386")
+}
+
+/// The address and size of the guards section, from the output of `objdump -h`
+fn guards_section(headers: &str) -> Option<(u64, u64)> {
+    headers.lines().find_map(|line| {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        if fields.get(1) != Some(&GUARDS_SECTION) {
+            return None;
+        }
+        let size = u64::from_str_radix(fields.get(2)?, 16).ok()?;
+        let address = u64::from_str_radix(fields.get(3)?, 16).ok()?;
+        Some((address, size))
+    })
+}
+
+/// The address of an instruction and the address objdump annotates it with (e.g., `# 4fa98
+/// <__sancov_gen_.12>` for a RIP-relative operand), from a line of `objdump -d`
+fn guard_reference(line: &str) -> Option<(u64, u64)> {
+    let (address, instruction) = line.trim_start().split_once(':')?;
+    let address = u64::from_str_radix(address, 16).ok()?;
+    let (_, comment) = instruction.rsplit_once('#')?;
+    let target = comment.split_whitespace().next()?;
+    let target = u64::from_str_radix(target.trim_start_matches("0x"), 16).ok()?;
+    Some((address, target))
+}
+
+/// Group the unstable edges by location, and describe the `top` locations that varied on the most
+/// inputs
+fn report(unstable: &BTreeMap<u32, usize>, locations: &HashMap<u32, String>, top: usize) -> String {
+    let mut by_location = BTreeMap::<&str, (usize, Vec<u32>)>::new();
+    for (&edge, &inputs) in unstable {
+        let location = locations
+            .get(&edge)
+            .map_or("an unknown location", String::as_str);
+        let (max_inputs, edges) = by_location.entry(location).or_default();
+        *max_inputs = (*max_inputs).max(inputs);
+        edges.push(edge);
+    }
+    let mut by_location = by_location.into_iter().collect::<Vec<_>>();
+    by_location.sort_by_key(|&(_, (inputs, _))| std::cmp::Reverse(inputs));
+
+    let mut report =
+        String::from("\nUnstable locations, by the number of inputs on which they varied:\n");
+    for (location, (inputs, edges)) in by_location.iter().take(top) {
+        let edges = edges
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        let _ = writeln!(
+            report,
+            "{inputs:>8}  {location} (edge{} {edges})",
+            if edges.contains(',') { "s" } else { "" }
+        );
+    }
+    if by_location.len() > top {
+        let _ = writeln!(report, "... and {} more", by_location.len() - top);
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unstable_edges_are_found() {
+        let maps = [
+            parse_map("000005:1\n000006:2\n000007:1\n"),
+            parse_map("000005:1\n000006:4\n"),
+            parse_map("000005:1\n000006:2\n000007:1\n"),
+        ];
+        assert_eq!(unstable_edges(&maps), BTreeSet::from([6, 7]));
+        assert!(unstable_edges(&maps[..1]).is_empty());
+    }
+
+    #[test]
+    fn objdump_output_is_parsed() {
+        let file_headers = "\
+target/debug/hello:     file format elf64-x86-64
+architecture: i386:x86-64, flags 0x00000150:
+HAS_SYMS, DYNAMIC, D_PAGED
+";
+        assert_eq!(architecture(file_headers), Some("i386:x86-64"));
+        assert!(is_x86("i386:x86-64"));
+        assert!(is_x86("i386"));
+        assert!(!is_x86("aarch64"));
+
+        let headers = "\
+Idx Name          Size      VMA               LMA               File off  Algn
+ 24 .data         00000100  0000000000062000  0000000000062000  00061000  2**3
+ 25 __sancov_guards 00000a10  0000000000062100  0000000000062100  00061100  2**2
+";
+        assert_eq!(guards_section(headers), Some((0x62100, 0xa10)));
+
+        assert_eq!(
+            guard_reference(
+                "   1a2b3:\tlea    0x47e45(%rip),%rdi        # 62100 <__sancov_gen_.12>"
+            ),
+            Some((0x1a2b3, 0x62100))
+        );
+        assert_eq!(
+            guard_reference("   1a2b3:\tleaq 0x47e45(%rip), %rdi  # 0x62100 <__sancov_gen_.12>"),
+            Some((0x1a2b3, 0x62100))
+        );
+        assert_eq!(guard_reference("   1a2b3:\tret"), None);
+        assert_eq!(guard_reference("000000000001a2b0 <main>:"), None);
+    }
+
+    #[test]
+    fn locations_are_grouped() {
+        let unstable = BTreeMap::from([(5, 1), (6, 3), (7, 2), (8, 1)]);
+        let locations = HashMap::from([
+            (6, "a at a.rs:1".to_owned()),
+            (7, "a at a.rs:1".to_owned()),
+            (8, "b at b.rs:2".to_owned()),
+        ]);
+        assert_eq!(
+            report(&unstable, &locations, 2),
+            "
+Unstable locations, by the number of inputs on which they varied:
+       3  a at a.rs:1 (edges 6, 7)
+       1  an unknown location (edge 5)
+... and 1 more
+"
+        );
+    }
+}
@@ -0,0 +1,319 @@
+//! Where `cargo afl` puts instrumented builds
+//!
+//! Instrumented builds use different `RUSTFLAGS` than normal ones. If they shared Cargo's target
+//! directory, every switch between `cargo build` and `cargo afl build` would rebuild everything.
+//! So, by default, instrumented builds go in `<target-dir>/afl/<key>`, where `<key>` describes the
+//! instrumentation configuration (e.g., `sancov` or `plugins-cmplog-address`).
+//!
+//! The key does not cover every flag that affects code generation, e.g., `-C target-cpu`,
+//! `-C opt-level`, or `--cfg`. Changing those rebuilds in the same directory, as Cargo's
+//! fingerprints include `RUSTFLAGS`.
+
+use crate::{manifest::Manifest, options::BuildOptions, wrapper::CrateFilter};
+use cargo_afl_common as common;
+use std::env;
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+const AFL_DIR_NAME: &str = "afl";
+/// Not a possible instrumentation key
+const COVERAGE_DIR_NAME: &str = "cov";
+
+/// The instrumentation settings that determine an instrumented build's directory
+struct Instrumentation<'a> {
+    plugins: bool,
+    cmplog: bool,
+    /// Flags that will be passed to rustc in addition to `cargo afl`'s own
+    user_rustflags: &'a str,
+    crate_filter: &'a CrateFilter,
+}
+
+impl Instrumentation<'_> {
+    fn key(&self) -> String {
+        let mut components = vec![if self.plugins { "plugins" } else { "sancov" }];
+        if self.cmplog {
+            components.push("cmplog");
+        }
+        components.extend(sanitizers(self.user_rustflags));
+        let mut key = components.join("-");
+        if !self.crate_filter.is_empty() {
+            key.push('-');
+            key.push_str(&self.crate_filter.key());
+        }
+        key
+    }
+}
+
+/// The directory for an instrumented build, or `None` if `args` choose one or Cargo's target
+/// directory could not be determined
+pub fn for_build(
+    args: &[OsString],
+    manifest: &Manifest,
+    options: &BuildOptions,
+    plugins: bool,
+) -> Option<PathBuf> {
+    if has_target_dir_arg(args) {
+        return None;
+    }
+    let key = key(
+        &options.crate_filter,
+        plugins,
+        env::var("AFL_LLVM_CMPLOG").is_ok(),
+    );
+    manifest
+        .target_directory()
+        .map(|target_dir| target_dir.join(AFL_DIR_NAME).join(key))
+}
+
+/// The key of a build with the given instrumentation and the user's `RUSTFLAGS`
+fn key(crate_filter: &CrateFilter, plugins: bool, cmplog: bool) -> String {
+    let user_rustflags = env::var("RUSTFLAGS").unwrap_or_default();
+    Instrumentation {
+        plugins,
+        cmplog,
+        user_rustflags: &user_rustflags,
+        crate_filter,
+    }
+    .key()
+}
+
+/// The directory for `cargo afl cov`'s coverage builds and reports, or `None` if Cargo's target
+/// directory could not be determined
+pub fn for_coverage(manifest: &Manifest) -> Option<PathBuf> {
+    manifest
+        .target_directory()
+        .map(|target_dir| target_dir.join(AFL_DIR_NAME).join(COVERAGE_DIR_NAME))
+}
+
+/// Replace fuzz target arguments that do not exist with the instrumented binaries they refer to.
+///
+/// An argument is replaced if it is a path inside Cargo's target directory (e.g.,
+/// `target/debug/examples/hello`), or if it follows `--` or `-c` and names a binary (e.g., `hello`),
+/// and the build with the instrumentation it needs contains a match: a CMPLOG build for the binary
+/// passed to `-c`, and a normal one for the others. If several binaries in that build match (e.g.,
+/// for different profiles), the most recently built one is used.
+pub fn resolve_fuzz_targets(args: &[OsString]) -> Vec<OsString> {
+    let mut resolver = None;
+    let mut previous = None;
+    args.iter()
+        .map(|arg| {
+            let cmplog = previous == Some("-c");
+            let is_fuzz_target = cmplog || previous == Some("--");
+            previous = arg.to_str();
+            if !may_refer_to_fuzz_target(arg, is_fuzz_target) || Path::new(arg).exists() {
+                return arg.clone();
+            }
+            let (manifest, options, plugins) = resolver.get_or_insert_with(|| {
+                let manifest = Manifest::load(&[]);
+                let options = BuildOptions::from_args(&mut Vec::new(), &manifest);
+                let plugins = uses_plugins(&manifest);
+                (manifest, options, plugins)
+            });
+            let Ok(options) = options else {
+                return arg.clone();
+            };
+            let key = key(&options.crate_filter, *plugins, cmplog);
+            resolve(manifest, &key, Path::new(arg), is_fuzz_target)
+                .map_or_else(|| arg.clone(), Into::into)
+        })
+        .collect()
+}
+
+/// Whether `cargo afl build` instruments with the AFL++ plugins, as `run_cargo` decides it
+fn uses_plugins(manifest: &Manifest) -> bool {
+    env::var("AFLRS_REQUIRE_PLUGINS").is_ok()
+        || (!manifest.uses_rust_runtime(&[]) && common::plugins_installed().unwrap_or(false))
+}
+
+/// Whether `arg` is a path, or a name that follows `--` or `-c`, as opposed to an option or an
+/// argument like `@@`, so that `cargo metadata` only runs if there is something to resolve
+fn may_refer_to_fuzz_target(arg: &OsString, is_fuzz_target: bool) -> bool {
+    if arg.to_string_lossy().starts_with('-') {
+        return false;
+    }
+    is_fuzz_target || Path::new(arg).components().count() > 1
+}
+
+fn resolve(manifest: &Manifest, key: &str, path: &Path, is_fuzz_target: bool) -> Option<PathBuf> {
+    let target_dir = manifest.target_directory()?;
+    let relative_path = if let Ok(relative_path) =
+        normalize(&env::current_dir().ok()?.join(path)).strip_prefix(&target_dir)
+    {
+        relative_path.to_path_buf()
+    } else if is_fuzz_target && path.components().count() == 1 {
+        PathBuf::from(path)
+    } else {
+        return None;
+    };
+
+    find(&target_dir.join(AFL_DIR_NAME).join(key), &relative_path)
+}
+
+/// The most recently built binary in `key_dir` at `relative_path`, which is either relative to a
+/// target directory or a bare name
+fn find(key_dir: &Path, relative_path: &Path) -> Option<PathBuf> {
+    // Binaries built with `--target` are in `<key>/<triple>/<profile>`, others in `<key>/<profile>`.
+    let triple_dirs = read_dirs(key_dir).filter(|dir| !is_profile_dir(dir));
+    let mut candidates = Vec::new();
+    for dir in std::iter::once(key_dir.to_path_buf()).chain(triple_dirs) {
+        if relative_path.components().count() == 1 {
+            // A bare name: look for a binary or an example in every profile directory.
+            for profile_dir in read_dirs(&dir).filter(|dir| is_profile_dir(dir)) {
+                candidates.push(profile_dir.join(relative_path));
+                candidates.push(profile_dir.join("examples").join(relative_path));
+            }
+        } else {
+            candidates.push(dir.join(relative_path));
+        }
+    }
+
+    candidates
+        .into_iter()
+        .filter_map(|path| Some((modified(&path)?, path)))
+        .max()
+        .map(|(_, path)| path)
+}
+
+fn is_profile_dir(dir: &Path) -> bool {
+    dir.join(".fingerprint").is_dir()
+}
+
+fn read_dirs(dir: &Path) -> impl Iterator<Item = PathBuf> {
+    dir.read_dir()
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    let metadata = path.metadata().ok()?;
+    if !metadata.is_file() {
+        return None;
+    }
+    metadata.modified().ok()
+}
+
+/// Remove `.` and `..` components from an absolute path without accessing the filesystem
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Sanitizers enabled by `-Z sanitizer=...` in `rustflags`
+fn sanitizers(rustflags: &str) -> Vec<&str> {
+    let mut sanitizers = Vec::new();
+    let mut flags = rustflags.split_whitespace();
+    while let Some(flag) = flags.next() {
+        let value = match flag {
+            "-Z" => flags
+                .next()
+                .and_then(|flag| flag.strip_prefix("sanitizer=")),
+            _ => flag.strip_prefix("-Zsanitizer="),
+        };
+        sanitizers.extend(value.into_iter().flat_map(|value| value.split(',')));
+    }
+    sanitizers
+}
+
+/// Whether `args` choose a target directory explicitly
+fn has_target_dir_arg(args: &[OsString]) -> bool {
+    args.iter()
+        .take_while(|arg| *arg != "--")
+        .filter_map(|arg| arg.to_str())
+        .any(|arg| arg == "--target-dir" || arg.starts_with("--target-dir="))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key() {
+        let instrumentation = Instrumentation {
+            plugins: false,
+            cmplog: false,
+            user_rustflags: "",
+            crate_filter: &CrateFilter::default(),
+        };
+        assert_eq!(instrumentation.key(), "sancov");
+
+        let instrumentation = Instrumentation {
+            plugins: true,
+            cmplog: true,
+            user_rustflags: "-C debuginfo=2 -Zsanitizer=address -Z sanitizer=memory,leak",
+            crate_filter: &CrateFilter::default(),
+        };
+        assert_eq!(instrumentation.key(), "plugins-cmplog-address-memory-leak");
+    }
+
+    #[test]
+    fn fuzz_target_arguments() {
+        let may_refer = |arg: &str, is_fuzz_target| {
+            may_refer_to_fuzz_target(&OsString::from(arg), is_fuzz_target)
+        };
+        assert!(may_refer("target/debug/examples/hello", false));
+        assert!(may_refer("./hello", false));
+        assert!(may_refer("hello", true));
+        assert!(!may_refer("hello", false));
+        assert!(!may_refer("@@", false));
+        assert!(!may_refer("-i", false));
+        assert!(!may_refer("--", true));
+    }
+
+    #[test]
+    fn existing_paths_are_kept() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let args = [OsString::from("--"), file.path().into()];
+        assert_eq!(resolve_fuzz_targets(&args), args);
+    }
+
+    #[test]
+    fn find_looks_only_in_the_key_directory() {
+        let target_dir = tempfile::tempdir().unwrap();
+        let afl_dir = target_dir.path().join(AFL_DIR_NAME);
+        let mut binaries = Vec::new();
+        for key in ["sancov", "sancov-cmplog"] {
+            let profile_dir = afl_dir
+                .join(key)
+                .join("x86_64-unknown-linux-gnu")
+                .join("debug");
+            std::fs::create_dir_all(profile_dir.join(".fingerprint")).unwrap();
+            std::fs::create_dir_all(profile_dir.join("examples")).unwrap();
+            let binary = profile_dir.join("examples").join("hello");
+            std::fs::write(&binary, key).unwrap();
+            binaries.push(binary);
+            // Make the CMPLOG build the most recent one.
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        for (key, binary) in ["sancov", "sancov-cmplog"].iter().zip(&binaries) {
+            let key_dir = afl_dir.join(key);
+            assert_eq!(find(&key_dir, Path::new("hello")).as_ref(), Some(binary));
+            assert_eq!(
+                find(&key_dir, Path::new("debug/examples/hello")).as_ref(),
+                Some(binary)
+            );
+        }
+        assert_eq!(find(&afl_dir.join("plugins"), Path::new("hello")), None);
+    }
+
+    #[test]
+    fn normalize_removes_dots() {
+        assert_eq!(
+            normalize(Path::new("/ws/cargo-afl/../target/./debug/hello")),
+            Path::new("/ws/target/debug/hello")
+        );
+    }
+}
@@ -0,0 +1,269 @@
+//! A `RUSTC_WRAPPER` that instruments only selected crates
+//!
+//! When `--instrument` or `--no-instrument` is used, `cargo afl` leaves the instrumentation flags
+//! out of `RUSTFLAGS` and sets `RUSTC_WRAPPER` to its own executable. Cargo then runs `cargo-afl
+//! <rustc> <args>...` for every crate, and the wrapper adds the instrumentation flags to the crates
+//! that match. This is the Rust analog of AFL++'s `AFL_LLVM_ALLOWLIST` and `AFL_LLVM_DENYLIST`.
+//!
+//! In plugin builds, the wrapper also collects each fuzz target's dictionary (see `dictionary.rs`).
+
+use crate::{dictionary, hash::fnv1a};
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::process::{self, Command};
+
+/// Set when `cargo-afl` is running as a `RUSTC_WRAPPER`; holds the instrumentation flags
+const INSTRUMENTATION_FLAGS: &str = "AFL_RS_INSTRUMENTATION_FLAGS";
+const INSTRUMENT: &str = "AFL_RS_INSTRUMENT";
+const NO_INSTRUMENT: &str = "AFL_RS_NO_INSTRUMENT";
+/// Set if only crates compiled with `--target` should be instrumented
+const TARGET_ONLY: &str = "AFL_RS_TARGET_ONLY";
+/// Set if Cargo was not passed `--target`, in which case the selected packages must be compiled with
+/// one from `build.target`
+const REQUIRE_BUILD_TARGET: &str = "AFL_RS_REQUIRE_BUILD_TARGET";
+/// Set if the wrapper should collect dictionaries
+const DICTIONARIES: &str = "AFL_RS_DICTIONARIES";
+/// The `RUSTC_WRAPPER` that the user had set, if any
+const INNER_RUSTC_WRAPPER: &str = "AFL_RS_INNER_RUSTC_WRAPPER";
+
+/// Which crates to instrument
+///
+/// Patterns are crate names, in which `*` matches any sequence of characters. Hyphens and
+/// underscores are interchangeable, as they are in package names.
+#[derive(Default)]
+pub struct CrateFilter {
+    /// If nonempty, only crates matching one of these patterns are instrumented
+    pub instrument: Vec<String>,
+    /// Crates matching one of these patterns are not instrumented
+    pub no_instrument: Vec<String>,
+}
+
+impl CrateFilter {
+    pub fn is_empty(&self) -> bool {
+        self.instrument.is_empty() && self.no_instrument.is_empty()
+    }
+
+    fn matches(&self, crate_name: &str) -> bool {
+        let matches_any = |patterns: &[String]| {
+            patterns
+                .iter()
+                .any(|pattern| glob_match(&normalize(pattern), &normalize(crate_name)))
+        };
+        (self.instrument.is_empty() || matches_any(&self.instrument))
+            && !matches_any(&self.no_instrument)
+    }
+
+    /// A short name for this filter, for use in target directory names. It is a fixed hash of the
+    /// patterns, so that it does not change between toolchains.
+    pub fn key(&self) -> String {
+        let patterns = [&self.instrument, &self.no_instrument].map(|patterns| patterns.join(","));
+        let hash = fnv1a(patterns.join("\0").as_bytes());
+        format!("filtered-{:08x}", hash & 0xffff_ffff)
+    }
+}
+
+/// Make Cargo run `cargo-afl` as a `RUSTC_WRAPPER` that adds `instrumentation_flags` to the crates
+/// that `filter` selects
+pub fn configure(
+    environment_variables: &mut HashMap<&str, String>,
+    filter: &CrateFilter,
+    instrumentation_flags: &str,
+    target_only: bool,
+) {
+    set_wrapper(environment_variables);
+    environment_variables.insert(INSTRUMENTATION_FLAGS, instrumentation_flags.to_owned());
+    environment_variables.insert(INSTRUMENT, filter.instrument.join(","));
+    environment_variables.insert(NO_INSTRUMENT, filter.no_instrument.join(","));
+    if target_only {
+        environment_variables.insert(TARGET_ONLY, "1".to_owned());
+    }
+}
+
+/// Make the wrapper fail if a crate of a selected package (other than a build script or a proc
+/// macro) is compiled without `--target`. Without `--target`, which means that `build.target` is not
+/// set either, instrumenting that crate would instrument every build script and proc macro too.
+pub fn require_build_target(environment_variables: &mut HashMap<&str, String>) {
+    environment_variables.insert(REQUIRE_BUILD_TARGET, "1".to_owned());
+}
+
+/// Make Cargo run `cargo-afl` as a `RUSTC_WRAPPER` that collects the tokens `afl-llvm-dict2file`
+/// finds into a dictionary for each binary
+pub fn collect_dictionaries(environment_variables: &mut HashMap<&str, String>) {
+    set_wrapper(environment_variables);
+    environment_variables.insert(DICTIONARIES, "1".to_owned());
+}
+
+fn set_wrapper(environment_variables: &mut HashMap<&str, String>) {
+    let current_exe = env::current_exe().unwrap();
+    if let Ok(inner) = env::var("RUSTC_WRAPPER")
+        && !inner.is_empty()
+    {
+        environment_variables.insert(INNER_RUSTC_WRAPPER, inner);
+    }
+    environment_variables.insert("RUSTC_WRAPPER", current_exe.display().to_string());
+}
+
+/// If `cargo-afl` was invoked as a `RUSTC_WRAPPER`, return the rustc command line it was given
+pub fn rustc_args() -> Option<Vec<OsString>> {
+    if env::var_os(INSTRUMENTATION_FLAGS).is_none() && env::var_os(DICTIONARIES).is_none() {
+        return None;
+    }
+    let args = env::args_os().skip(1).collect::<Vec<_>>();
+    // Cargo invokes subcommands as `cargo-afl afl ...`.
+    if args.first().is_none_or(|arg| arg == "afl") {
+        return None;
+    }
+    Some(args)
+}
+
+/// Run rustc, adding the instrumentation flags if the crate being compiled is selected
+pub fn run(rustc_args: &[OsString]) -> ! {
+    let filter = CrateFilter {
+        instrument: list_from_env(INSTRUMENT),
+        no_instrument: list_from_env(NO_INSTRUMENT),
+    };
+    let target_only = env::var_os(TARGET_ONLY).is_some();
+    let has_target = rustc_args.iter().any(|arg| arg == "--target");
+    let crate_name = crate_name(rustc_args);
+    if env::var_os(REQUIRE_BUILD_TARGET).is_some()
+        && !has_target
+        && env::var_os("CARGO_PRIMARY_PACKAGE").is_some()
+        && crate_name
+            .as_ref()
+            .is_some_and(|crate_name| !crate_name.starts_with("build_script_"))
+        && !is_proc_macro(rustc_args)
+    {
+        eprintln!(
+            "Error: `--no-host-target` (or `host-target = false`) requires `--target` or \
+             `build.target`, as build scripts and proc macros would be instrumented otherwise"
+        );
+        process::exit(1);
+    }
+    let instrument = crate_name
+        .is_some_and(|crate_name| (!target_only || has_target) && filter.matches(&crate_name));
+
+    let mut command = if let Some(inner) = env::var_os(INNER_RUSTC_WRAPPER) {
+        let mut command = Command::new(inner);
+        command.args(rustc_args);
+        command
+    } else {
+        let mut command = Command::new(&rustc_args[0]);
+        command.args(&rustc_args[1..]);
+        command
+    };
+    if instrument {
+        let flags = env::var(INSTRUMENTATION_FLAGS).unwrap_or_default();
+        command.args(flags.split_whitespace());
+    }
+    let dictionary = env::var_os(DICTIONARIES)
+        .and_then(|_| dictionary::prepare(rustc_args))
+        .inspect(|dictionary| {
+            command.env("AFL_LLVM_DICT2FILE", dictionary);
+        });
+
+    let status = command.status().unwrap();
+    if status.success()
+        && let Some(dictionary) = dictionary
+        && let Err(error) = dictionary::finish(rustc_args, &dictionary)
+    {
+        eprintln!("Warning: could not write a dictionary: {error:#}");
+    }
+    process::exit(status.code().unwrap_or(1));
+}
+
+fn crate_name(rustc_args: &[OsString]) -> Option<String> {
+    let index = rustc_args.iter().position(|arg| arg == "--crate-name")?;
+    rustc_args
+        .get(index + 1)
+        .map(|arg| arg.to_string_lossy().into_owned())
+}
+
+fn is_proc_macro(rustc_args: &[OsString]) -> bool {
+    rustc_args
+        .windows(2)
+        .any(|args| args[0] == "--crate-type" && args[1] == "proc-macro")
+}
+
+fn list_from_env(key: &str) -> Vec<String> {
+    env::var(key)
+        .unwrap_or_default()
+        .split(',')
+        .filter(|s| !s.is_empty())
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+fn normalize(name: &str) -> String {
+    name.replace('-', "_")
+}
+
+/// Match `text` against `pattern`, in which `*` matches any sequence of characters
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let Some((prefix, rest)) = pattern.split_once('*') else {
+        return pattern == text;
+    };
+    let Some(mut text) = text.strip_prefix(prefix) else {
+        return false;
+    };
+    let mut parts = rest.split('*').peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            return text.ends_with(part);
+        }
+        let Some(index) = text.find(part) else {
+            return false;
+        };
+        text = &text[index + part.len()..];
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("serde", "serde"));
+        assert!(!glob_match("serde", "serde_json"));
+        assert!(glob_match("serde*", "serde_json"));
+        assert!(glob_match("*_parser", "json_parser"));
+        assert!(glob_match("a*b*c", "a_x_b_y_c"));
+        assert!(!glob_match("a*b*c", "a_x_c"));
+    }
+
+    #[test]
+    fn filter() {
+        let filter = CrateFilter {
+            instrument: vec![String::from("my-parser*")],
+            no_instrument: vec![String::from("my_parser_macros")],
+        };
+        assert!(filter.matches("my_parser"));
+        assert!(filter.matches("my_parser_core"));
+        assert!(!filter.matches("my_parser_macros"));
+        assert!(!filter.matches("serde"));
+
+        let filter = CrateFilter {
+            instrument: Vec::new(),
+            no_instrument: vec![String::from("regex"), String::from("tokio")],
+        };
+        assert!(filter.matches("my_parser"));
+        assert!(!filter.matches("regex"));
+    }
+
+    #[test]
+    fn key_is_fixed() {
+        let filter = CrateFilter {
+            instrument: vec![String::from("my-parser*")],
+            no_instrument: vec![String::from("my_parser_macros")],
+        };
+        assert_eq!(filter.key(), "filtered-4afe67bf");
+
+        let swapped = CrateFilter {
+            instrument: filter.no_instrument.clone(),
+            no_instrument: filter.instrument.clone(),
+        };
+        assert_ne!(swapped.key(), filter.key());
+    }
+}
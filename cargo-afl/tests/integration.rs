@@ -1,4 +1,5 @@
 use cargo_afl_common as common;
+use predicates::prelude::PredicateBooleanExt;
 use std::{
     io::Write,
     path,
@@ -19,8 +20,23 @@ fn cargo_afl_path() -> path::PathBuf {
     target_dir_path().join("cargo-afl")
 }
 
+/// The example built by `cargo afl build --examples`. Not `target/debug/examples`, which may hold
+/// uninstrumented builds that `cargo afl fuzz` would run as they are.
 fn examples_path(name: &str) -> path::PathBuf {
-    target_dir_path().join("examples").join(name)
+    let instrumentation = if common::plugins_installed().unwrap_or_default() {
+        "plugins"
+    } else {
+        "sancov"
+    };
+    target_dir_path()
+        .parent()
+        .unwrap()
+        .join("afl")
+        .join(instrumentation)
+        .join(host_triple())
+        .join("debug")
+        .join("examples")
+        .join(name)
 }
 
 fn input_path() -> path::PathBuf {
@@ -32,6 +48,39 @@ fn integration() {
     fuzz_example("hello", true);
 }
 
+#[test]
+fn integration_crash_records() {
+    let temp_dir = fuzz_example_with_envs("hello", 5, &[("AFL_BENCH_UNTIL_CRASH", "1")]);
+    let crash_records = temp_dir.path().join("default").join("crash_records");
+    let record = std::fs::read_dir(&crash_records)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| {
+            path.file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with("id:"))
+                && path.extension().is_some_and(|extension| extension == "txt")
+        })
+        .expect("no crash record was copied next to the crashes directory");
+    let record = std::fs::read_to_string(record).unwrap();
+    assert!(record.contains("Crash!"), "{record}");
+}
+
+#[test]
+fn integration_targets() {
+    // Only the selected target runs, so only its crashes are found.
+    let temp_dir = fuzz_example_with_envs(
+        "targets",
+        5,
+        &[("AFL_BENCH_UNTIL_CRASH", "1"), ("AFL_RS_TARGET", "goodbye")],
+    );
+    let crashes = std::fs::read_dir(temp_dir.path().join("default").join("crashes"))
+        .unwrap()
+        .filter_map(|entry| std::fs::read(entry.unwrap().path()).ok())
+        .collect::<Vec<_>>();
+    assert!(!crashes.is_empty());
+    assert!(crashes.iter().all(|crash| crash.first() == Some(&b'z')));
+}
+
 #[test]
 fn integration_cfg() {
     for cfg_fuzzing in [false, true] {
@@ -100,6 +149,299 @@ fn integration_maze() {
     unreachable!();
 }
 
+#[test]
+fn integration_rust_runtime() {
+    let target_dir = tempfile::TempDir::new().expect("Could not create temporary directory");
+    assert_cmd::Command::new(cargo_afl_path())
+        .arg("afl")
+        .arg("build")
+        .arg("--example")
+        .arg("hello")
+        .arg("--no-default-features")
+        .arg("--features")
+        .arg("rust_runtime")
+        .arg("--target-dir")
+        .arg(target_dir.path())
+        .arg("--manifest-path")
+        .arg("../afl/Cargo.toml")
+        .assert()
+        .success()
+        // Without the `c_runtime` feature, no C code is compiled.
+        .stderr(predicates::str::contains("afl-sys").not());
+
+    // `cargo afl build` passes the host triple as `--target`.
+    let hello_path = target_dir
+        .path()
+        .join(host_triple())
+        .join("debug")
+        .join("examples")
+        .join("hello");
+    let temp_dir = fuzz_with_envs(&hello_path, 5, &[("AFL_BENCH_UNTIL_CRASH", "1")]);
+    let crashes = std::fs::read_dir(temp_dir.path().join("default").join("crashes"))
+        .unwrap()
+        .count();
+    assert!(crashes >= 1);
+}
+
+#[test]
+fn integration_without_afl_dependency() {
+    let temp_dir = tempfile::TempDir::new().expect("Could not create temporary directory");
+    assert_cmd::Command::new("cargo")
+        .args(["init", "--name", "no_afl"])
+        .arg(temp_dir.path())
+        .assert()
+        .success();
+
+    // Neither the binary nor its unit tests link `afl`, so the runtime comes from `cargo afl config
+    // --build`.
+    for subcommand in ["build", "test"] {
+        assert_cmd::Command::new(cargo_afl_path())
+            .args(["afl", subcommand, "--manifest-path"])
+            .arg(temp_dir.path().join("Cargo.toml"))
+            .assert()
+            .success();
+    }
+    // The key directory depends on whether the AFL++ plugins are installed.
+    let built = std::fs::read_dir(temp_dir.path().join("target").join("afl"))
+        .unwrap()
+        .any(|entry| {
+            let binary = entry.unwrap().path().join(host_triple()).join("debug");
+            binary.join("no_afl").is_file()
+        });
+    assert!(built);
+}
+
+#[test]
+fn integration_decoders() {
+    let target_dir = tempfile::TempDir::new().expect("Could not create temporary directory");
+    assert_cmd::Command::new(cargo_afl_path())
+        .args(["afl", "build", "--example", "decoders"])
+        .args(["--features", "bincode,json,postcard"])
+        .arg("--target-dir")
+        .arg(target_dir.path())
+        .arg("--manifest-path")
+        .arg("../afl/Cargo.toml")
+        .assert()
+        .success();
+    let decoders_path = target_dir
+        .path()
+        .join(host_triple())
+        .join("debug")
+        .join("examples")
+        .join("decoders");
+
+    // Each decoder, an input that decodes to the value its target crashes on, and one that does not
+    let cases: &[(&str, &[u8], &[u8])] = &[
+        ("str", b"crash", b"crash\xff"),
+        ("string", b"crash", b"crash\xff"),
+        // Without `arbitrary_take_rest`, the slice's length would be read from the input.
+        ("take_rest", b"\x01crash", b"\x01\x05crash"),
+        ("arbitrary", b"\x01\x02", b"\x01\x03"),
+        ("json", br#"{"x":1,"y":2}"#, br#"{"x":1,"y":3}"#),
+        ("postcard", b"\x01\x02", b"\x01\x03"),
+        ("bincode", b"\x01\x02", b"\x01\x03"),
+    ];
+    let input_dir = tempfile::TempDir::new().expect("Could not create temporary directory");
+    let crashes = |decoder: &str, input: &[u8]| {
+        let input_path = input_dir.path().join("input");
+        std::fs::write(&input_path, input).unwrap();
+        !process::Command::new(&decoders_path)
+            .arg(&input_path)
+            .env("DECODER", decoder)
+            .stderr(process::Stdio::null())
+            .status()
+            .expect("Could not run the decoders example")
+            .success()
+    };
+    for &(decoder, crashing, other) in cases {
+        assert!(crashes(decoder, crashing), "{decoder}: {crashing:?}");
+        assert!(!crashes(decoder, other), "{decoder}: {other:?}");
+    }
+}
+
+#[test]
+fn integration_malloc_limit() {
+    // The default limit is 2048 MB.
+    let output = run_allocations_example(b"big", &[]);
+    assert!(output.status.success());
+
+    let output = run_allocations_example(b"big", &[("AFL_RS_MALLOC_LIMIT_MB", "1")]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("afl: malloc limit exceeded"), "{stderr}");
+
+    // `realloc` is counted by how much the size changes, and tracked without leaving a record
+    // behind.
+    for envs in [&[][..], &[("AFL_RS_DETECT_LEAKS", "1")]] {
+        let output = run_allocations_example(b"realloc", envs);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "{stderr}");
+    }
+}
+
+#[test]
+fn integration_detect_leaks() {
+    let output = run_allocations_example(b"leak", &[]);
+    assert!(output.status.success());
+
+    // The 4096 bytes freed were allocated before the iteration, so they do not hide the 64 leaked.
+    let output = run_allocations_example(b"leak", &[("AFL_RS_DETECT_LEAKS", "1")]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("afl: leak detected: the input retained 64 bytes in 1 allocation\n"),
+        "{stderr}"
+    );
+}
+
+/// Run the `allocations` example, which is built once for all tests, on `input`
+fn run_allocations_example(input: &[u8], envs: &[(&str, &str)]) -> process::Output {
+    static TARGET_DIR: std::sync::OnceLock<tempfile::TempDir> = std::sync::OnceLock::new();
+    let target_dir = TARGET_DIR.get_or_init(|| {
+        let target_dir = tempfile::TempDir::new().expect("Could not create temporary directory");
+        assert_cmd::Command::new(cargo_afl_path())
+            .args(["afl", "build", "--example", "allocations"])
+            .arg("--target-dir")
+            .arg(target_dir.path())
+            .arg("--manifest-path")
+            .arg("../afl/Cargo.toml")
+            .assert()
+            .success();
+        target_dir
+    });
+    let allocations_path = target_dir
+        .path()
+        .join(host_triple())
+        .join("debug")
+        .join("examples")
+        .join("allocations");
+
+    let input_file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(input_file.path(), input).unwrap();
+    process::Command::new(allocations_path)
+        .arg(input_file.path())
+        .envs(envs.iter().copied())
+        .output()
+        .expect("Could not run the allocations example")
+}
+
+#[test]
+fn integration_async() {
+    // The built-in executor, then tokio's
+    for features in [&[][..], &["--features", "tokio"]] {
+        let target_dir = tempfile::TempDir::new().expect("Could not create temporary directory");
+        assert_cmd::Command::new(cargo_afl_path())
+            .args(["afl", "build", "--example", "async"])
+            .args(features)
+            .arg("--target-dir")
+            .arg(target_dir.path())
+            .arg("--manifest-path")
+            .arg("../afl/Cargo.toml")
+            .assert()
+            .success();
+
+        let async_path = target_dir
+            .path()
+            .join(host_triple())
+            .join("debug")
+            .join("examples")
+            .join("async");
+        let temp_dir = fuzz_with_envs(&async_path, 5, &[("AFL_BENCH_UNTIL_CRASH", "1")]);
+        let crashes = std::fs::read_dir(temp_dir.path().join("default").join("crashes"))
+            .unwrap()
+            .count();
+        assert!(crashes >= 1, "no crashes with {features:?}");
+    }
+}
+
+#[test]
+fn integration_reject_seed() {
+    // Instrument only the example, so that the rejected seed leaves no coverage of its own.
+    let target_dir = tempfile::TempDir::new().expect("Could not create temporary directory");
+    assert_cmd::Command::new(cargo_afl_path())
+        .args([
+            "afl",
+            "build",
+            "--example",
+            "reject",
+            "--instrument",
+            "reject",
+        ])
+        .arg("--target-dir")
+        .arg(target_dir.path())
+        .arg("--manifest-path")
+        .arg("../afl/Cargo.toml")
+        .assert()
+        .success();
+
+    let reject_path = target_dir
+        .path()
+        .join(host_triple())
+        .join("debug")
+        .join("examples")
+        .join("reject");
+    let temp_dir = fuzz_with_envs(&reject_path, 5, &[("AFL_BENCH_UNTIL_CRASH", "1")]);
+    assert!(
+        temp_dir
+            .path()
+            .join("default")
+            .join("fuzzer_stats")
+            .is_file()
+    );
+}
+
+#[test]
+fn integration_inputs() {
+    let target_dir = tempfile::TempDir::new().expect("Could not create temporary directory");
+    assert_cmd::Command::new(cargo_afl_path())
+        .args(["afl", "build", "--example", "inputs"])
+        .arg("--target-dir")
+        .arg(target_dir.path())
+        .arg("--manifest-path")
+        .arg("../afl/Cargo.toml")
+        .assert()
+        .success();
+    let inputs_path = target_dir
+        .path()
+        .join(host_triple())
+        .join("debug")
+        .join("examples")
+        .join("inputs");
+
+    // Outside of afl-fuzz, each argument is read as a file, and unreadable files are skipped.
+    let input_dir = tempfile::TempDir::new().expect("Could not create temporary directory");
+    let (first, missing, second) = (
+        input_dir.path().join("first"),
+        input_dir.path().join("missing"),
+        input_dir.path().join("second"),
+    );
+    std::fs::write(&first, "xyz").unwrap();
+    std::fs::write(&second, "hello").unwrap();
+    assert_cmd::Command::new(&inputs_path)
+        .args([&first, &missing, &second])
+        .assert()
+        .success()
+        .stdout("3\n5\n")
+        .stderr(predicates::str::contains(format!(
+            "afl: could not read `{}`",
+            missing.display()
+        )));
+
+    // Without arguments, standard input is read.
+    assert_cmd::Command::new(&inputs_path)
+        .write_stdin("1234")
+        .assert()
+        .success()
+        .stdout("4\n");
+
+    // Under afl-fuzz, the rejected seed does not stop the dry run, and crashes are found.
+    let temp_dir = fuzz_with_envs(&inputs_path, 5, &[("AFL_BENCH_UNTIL_CRASH", "1")]);
+    let crashes = std::fs::read_dir(temp_dir.path().join("default").join("crashes"))
+        .unwrap()
+        .count();
+    assert!(crashes >= 1);
+}
+
 #[test]
 fn integration_fuzz_with_reset() {
     // Run without reset (expect low stability)
@@ -125,6 +467,167 @@ fn integration_fuzz_with_reset() {
     );
 }
 
+#[test]
+fn integration_fuzz_with_reset_hook() {
+    // Run with a registered reset hook (expect high stability, as with `fuzz_with_reset!`)
+    let dir_with_hook = fuzz_example_with_envs("reset_demo", 15, &[("USE_RESET", "hook")]);
+
+    // Run with a hook registered by `#[afl::on_reset]`
+    let dir_with_attribute = fuzz_example_with_envs("reset_attribute", 15, &[]);
+
+    let stability_with_hook = parse_stability(dir_with_hook.path());
+    let stability_with_attribute = parse_stability(dir_with_attribute.path());
+
+    // See `integration_fuzz_with_reset`.
+    let min_stability_expected = 80.0;
+
+    assert!(
+        stability_with_hook > min_stability_expected,
+        "Stability with a reset hook ({stability_with_hook}%) should be above {min_stability_expected}%"
+    );
+    assert!(
+        stability_with_attribute > min_stability_expected,
+        "Stability with `#[afl::on_reset]` ({stability_with_attribute}%) should be above \
+         {min_stability_expected}%"
+    );
+
+    // afl-fuzz's stability also depends on how often it restarts the target, so check with
+    // `cargo afl stability` that both hooks leave no edge unstable (see `integration_stability`).
+    let with_hook = stability_report("reset_demo", &[("USE_RESET", "hook")]);
+    assert!(with_hook.contains("(100.00% stable)"), "{with_hook}");
+    let with_attribute = stability_report("reset_attribute", &[]);
+    assert!(
+        with_attribute.contains("(100.00% stable)"),
+        "{with_attribute}"
+    );
+}
+
+#[test]
+fn integration_stability() {
+    let stability = |envs: &[(&str, &str)]| stability_report("reset_demo", envs);
+
+    // The cache is filled on the first iteration only.
+    let without_reset = stability(&[]);
+    assert!(
+        without_reset.contains("reset_demo::fuzz_body at "),
+        "{without_reset}"
+    );
+
+    let with_reset = stability(&[("USE_RESET", "1")]);
+    assert!(with_reset.contains("(100.00% stable)"), "{with_reset}");
+}
+
+#[test]
+fn integration_watchdog() {
+    let target_dir = tempfile::TempDir::new().expect("Could not create temporary directory");
+    assert_cmd::Command::new(cargo_afl_path())
+        .args(["afl", "build", "--example", "hang"])
+        .arg("--target-dir")
+        .arg(target_dir.path())
+        .arg("--manifest-path")
+        .arg("../afl/Cargo.toml")
+        .assert()
+        .success();
+    let hang_path = target_dir
+        .path()
+        .join(host_triple())
+        .join("debug")
+        .join("examples")
+        .join("hang");
+
+    // Replaying a hang shows where it hangs.
+    let input_file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(input_file.path(), "a").unwrap();
+    let output = process::Command::new(&hang_path)
+        .arg(input_file.path())
+        .env("AFL_RS_TIMEOUT", "100")
+        .output()
+        .expect("Could not run the hang example");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("afl: an iteration timed out after 100 ms, on an input of 1 bytes"),
+        "{stderr}"
+    );
+    assert!(stderr.contains("hang::spin"), "{stderr}");
+
+    // Under afl-fuzz, with `-t`, the hang is a crash.
+    let temp_dir = fuzz_with_args(
+        &hang_path,
+        10,
+        &["-t", "1000"],
+        &[("AFL_BENCH_UNTIL_CRASH", "1")],
+    );
+    let crashes = std::fs::read_dir(temp_dir.path().join("default").join("crashes"))
+        .unwrap()
+        .count();
+    assert!(crashes >= 1);
+}
+
+#[test]
+fn integration_dictionary() {
+    // Release builds link with `--gc-sections`, which must keep the `#[used]` tokens.
+    let target_dir = tempfile::TempDir::new().expect("Could not create temporary directory");
+    assert_cmd::Command::new(cargo_afl_path())
+        .args(["afl", "build", "--release", "--example", "dictionary"])
+        .arg("--target-dir")
+        .arg(target_dir.path())
+        .arg("--manifest-path")
+        .arg("../afl/Cargo.toml")
+        .assert()
+        .success();
+    let dictionary_path = target_dir
+        .path()
+        .join(host_triple())
+        .join("release")
+        .join("examples")
+        .join("dictionary");
+
+    let output_dir = tempfile::TempDir::new().expect("Could not create temporary directory");
+    let output = process::Command::new(cargo_afl_path())
+        .args(["afl", "fuzz", "-V", "1", "-i"])
+        .arg(input_path())
+        .arg("-o")
+        .arg(output_dir.path())
+        .arg(dictionary_path)
+        .env("AFL_NO_UI", "1")
+        .output()
+        .expect("Could not run cargo afl fuzz");
+    let log = String::from_utf8_lossy(&output.stdout) + String::from_utf8_lossy(&output.stderr);
+    assert!(log.contains("Loaded 3 autodictionary entries"), "{log}");
+}
+
+#[test]
+fn integration_cov() {
+    let output_dir = tempfile::TempDir::new().unwrap();
+    let status = process::Command::new(cargo_afl_path())
+        .args(["afl", "cov", "-i"])
+        .arg(input_path())
+        .args(["--only", "afl", "--output-dir"])
+        .arg(output_dir.path())
+        .args(["hello", "--", "--manifest-path"])
+        .arg(path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../afl/Cargo.toml"))
+        .status()
+        .expect("Could not run cargo afl cov");
+    assert!(status.success());
+    let lcov = std::fs::read_to_string(output_dir.path().join("lcov.info")).unwrap();
+    assert!(lcov.contains("examples/hello.rs"), "{lcov}");
+    assert!(output_dir.path().join("html").join("index.html").is_file());
+}
+
+/// Run `cargo afl stability` on the example `name`, and return its report
+fn stability_report(name: &str, envs: &[(&str, &str)]) -> String {
+    let output = process::Command::new(cargo_afl_path())
+        .args(["afl", "stability", "-i"])
+        .arg(input_path())
+        .arg(examples_path(name))
+        .envs(envs.iter().copied())
+        .output()
+        .expect("Could not run cargo afl stability");
+    assert!(output.status.success(), "{output:?}");
+    String::from_utf8(output.stdout).unwrap()
+}
+
 fn fuzz_example(name: &str, should_crash: bool) {
     let temp_dir = fuzz_example_with_envs(name, 5, &[("AFL_BENCH_UNTIL_CRASH", "1")]);
     let temp_dir_path = temp_dir.path();
@@ -143,6 +646,23 @@ fn fuzz_example_with_envs(
     name: &str,
     timeout_secs: u32,
     envs: &[(&str, &str)],
+) -> tempfile::TempDir {
+    fuzz_with_envs(&examples_path(name), timeout_secs, envs)
+}
+
+fn fuzz_with_envs(
+    fuzz_target: &path::Path,
+    timeout_secs: u32,
+    envs: &[(&str, &str)],
+) -> tempfile::TempDir {
+    fuzz_with_args(fuzz_target, timeout_secs, &[], envs)
+}
+
+fn fuzz_with_args(
+    fuzz_target: &path::Path,
+    timeout_secs: u32,
+    args: &[&str],
+    envs: &[(&str, &str)],
 ) -> tempfile::TempDir {
     let temp_dir = tempfile::TempDir::new().expect("Could not create temporary directory");
     let _: ExitStatus = process::Command::new(cargo_afl_path())
@@ -153,7 +673,8 @@ fn fuzz_example_with_envs(
         .arg("-o")
         .arg(temp_dir.path())
         .args(["-V", &timeout_secs.to_string()])
-        .arg(examples_path(name))
+        .args(args)
+        .arg(fuzz_target)
         .env("AFL_NO_CRASH_README", "1")
         .env("AFL_NO_UI", "1")
         .envs(envs.iter().copied())
@@ -164,6 +685,19 @@ fn fuzz_example_with_envs(
     temp_dir
 }
 
+fn host_triple() -> String {
+    let output = process::Command::new("rustc")
+        .arg("-vV")
+        .output()
+        .expect("Could not run rustc");
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .find_map(|line| line.strip_prefix("host: "))
+        .expect("rustc -vV did not print the host triple")
+        .to_owned()
+}
+
 fn parse_stability(output_dir: &path::Path) -> f64 {
     let stats_path = output_dir.join("default").join("fuzzer_stats");
     let contents = std::fs::read_to_string(&stats_path)